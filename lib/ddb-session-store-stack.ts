@@ -3,6 +3,7 @@ import { Table, AttributeType, BillingMode } from "aws-cdk-lib/aws-dynamodb";
 import { Construct } from "constructs";
 import * as ec2 from "aws-cdk-lib/aws-ec2";
 import * as lambda from "aws-cdk-lib/aws-lambda";
+import * as secretsmanager from "aws-cdk-lib/aws-secretsmanager";
 import * as elbv2 from "aws-cdk-lib/aws-elasticloadbalancingv2";
 import * as targets from "aws-cdk-lib/aws-elasticloadbalancingv2-targets";
 import { Code } from "aws-cdk-lib/aws-lambda";
@@ -29,8 +30,23 @@ export class DdbSessionStoreStack extends cdk.Stack {
       partitionKey: { name: "GSI1PK", type: AttributeType.STRING },
     });
 
-    const albApi = this.createAlbApi(sessionTable);
-    const httpApi = this.createHttpApi(sessionTable);
+    const usersTable = new Table(this, "UsersTable", {
+      partitionKey: { name: "PK", type: AttributeType.STRING },
+      billingMode: BillingMode.PAY_PER_REQUEST,
+      removalPolicy: RemovalPolicy.DESTROY,
+    });
+
+    // the functions fetch it at startup. Rotate by replacing the value with
+    // `k2=<new secret>,k1=<old secret>`, see SessionConfig::from_env.
+    const hashSecret = new secretsmanager.Secret(this, "SessionHashSecret", {
+      generateSecretString: {
+        passwordLength: 64,
+        excludePunctuation: true,
+      },
+    });
+
+    const albApi = this.createAlbApi(sessionTable, usersTable, hashSecret);
+    const httpApi = this.createHttpApi(sessionTable, usersTable, hashSecret);
 
     new cdk.CfnOutput(this, "HttpApiEndpoint", {
       value: httpApi.apiEndpoint,
@@ -42,7 +58,11 @@ export class DdbSessionStoreStack extends cdk.Stack {
     });
   }
 
-  private createAlbApi(sessionTable: Table) {
+  private createAlbApi(
+    sessionTable: Table,
+    usersTable: Table,
+    hashSecret: secretsmanager.ISecret
+  ) {
     const vpc = ec2.Vpc.fromLookup(this, "DefaultVpc", {
       vpcId: "vpc-090b0aa30d42dd996",
     });
@@ -58,10 +78,14 @@ export class DdbSessionStoreStack extends cdk.Stack {
       functionName: "alb-rust-session-svc",
       environment: {
         TABLE_NAME: sessionTable.tableName,
+        USERS_TABLE_NAME: usersTable.tableName,
+        SESSION_HASH_KEYS_SECRET_ARN: hashSecret.secretArn,
         RUST_LOG: "info",
       },
     });
     sessionTable.grantReadWriteData(sessionSvcFn);
+    hashSecret.grantRead(sessionSvcFn);
+    usersTable.grantReadData(sessionSvcFn);
 
     const listener = alb.addListener("Listener", {
       port: 80,
@@ -71,7 +95,12 @@ export class DdbSessionStoreStack extends cdk.Stack {
     listener.addTargets("GetSessionTarget", {
       targets: [new targets.LambdaTarget(sessionSvcFn)],
       conditions: [
-        elbv2.ListenerCondition.pathPatterns(["/sessions", "/sessions/*"]),
+        elbv2.ListenerCondition.pathPatterns([
+          "/sessions",
+          "/sessions/*",
+          "/users/*",
+          "/.well-known/*",
+        ]),
       ],
       priority: 1,
       healthCheck: {
@@ -82,8 +111,17 @@ export class DdbSessionStoreStack extends cdk.Stack {
     return alb;
   }
 
-  private createHttpApi(sessionTable: Table) {
-    const fns = this.createFunctions(sessionTable, "apigw");
+  private createHttpApi(
+    sessionTable: Table,
+    usersTable: Table,
+    hashSecret: secretsmanager.ISecret
+  ) {
+    const fns = this.createFunctions(
+      sessionTable,
+      usersTable,
+      hashSecret,
+      "apigw"
+    );
     const httpApi = new HttpApi(this, "HttpApi", {
       apiName: "rust-ddb-session-api",
     });
@@ -104,7 +142,25 @@ export class DdbSessionStoreStack extends cdk.Stack {
     });
 
     httpApi.addRoutes({
-      path: "/sessions/{username}",
+      path: "/sessions",
+      methods: [HttpMethod.DELETE],
+      integration: new HttpLambdaIntegration(
+        "delete-current-session",
+        fns.deleteSessionFn
+      ),
+    });
+
+    httpApi.addRoutes({
+      path: "/sessions/{id}",
+      methods: [HttpMethod.DELETE],
+      integration: new HttpLambdaIntegration(
+        "delete-session",
+        fns.deleteSessionFn
+      ),
+    });
+
+    httpApi.addRoutes({
+      path: "/users/{username}/sessions",
       methods: [HttpMethod.DELETE],
       integration: new HttpLambdaIntegration(
         "delete-user-sessions",
@@ -115,7 +171,12 @@ export class DdbSessionStoreStack extends cdk.Stack {
     return httpApi;
   }
 
-  createFunctions(sessionTable: Table, prefix: string = "") {
+  createFunctions(
+    sessionTable: Table,
+    usersTable: Table,
+    hashSecret: secretsmanager.ISecret,
+    prefix: string = ""
+  ) {
     const idPrefix = capitalize(prefix);
     const namePrefix = prefix ? `${prefix}-` : "";
 
@@ -126,10 +187,12 @@ export class DdbSessionStoreStack extends cdk.Stack {
       functionName: `${namePrefix}rust-get-session`,
       environment: {
         TABLE_NAME: sessionTable.tableName,
+        SESSION_HASH_KEYS_SECRET_ARN: hashSecret.secretArn,
         RUST_LOG: "info",
       },
     });
     sessionTable.grantReadData(getSessionFn);
+    hashSecret.grantRead(getSessionFn);
 
     const createSessionFn = new lambda.Function(
       this,
@@ -141,11 +204,15 @@ export class DdbSessionStoreStack extends cdk.Stack {
         functionName: `${namePrefix}rust-create-session`,
         environment: {
           TABLE_NAME: sessionTable.tableName,
+          USERS_TABLE_NAME: usersTable.tableName,
+          SESSION_HASH_KEYS_SECRET_ARN: hashSecret.secretArn,
           RUST_LOG: "info",
         },
       }
     );
     sessionTable.grantWriteData(createSessionFn);
+    hashSecret.grantRead(createSessionFn);
+    usersTable.grantReadData(createSessionFn);
 
     const deleteUserSessionsFn = new lambda.Function(
       this,
@@ -157,16 +224,37 @@ export class DdbSessionStoreStack extends cdk.Stack {
         functionName: `${namePrefix}rust-delete-user-sessions`,
         environment: {
           TABLE_NAME: sessionTable.tableName,
+          SESSION_HASH_KEYS_SECRET_ARN: hashSecret.secretArn,
           RUST_LOG: "info",
         },
       }
     );
     sessionTable.grantReadWriteData(deleteUserSessionsFn);
+    hashSecret.grantRead(deleteUserSessionsFn);
+
+    const deleteSessionFn = new lambda.Function(
+      this,
+      `${idPrefix}DeleteSessionFn`,
+      {
+        code: Code.fromAsset("target/lambda/delete-session"),
+        runtime: lambda.Runtime.PROVIDED_AL2,
+        handler: "bootstrap",
+        functionName: `${namePrefix}rust-delete-session`,
+        environment: {
+          TABLE_NAME: sessionTable.tableName,
+          SESSION_HASH_KEYS_SECRET_ARN: hashSecret.secretArn,
+          RUST_LOG: "info",
+        },
+      }
+    );
+    sessionTable.grantReadWriteData(deleteSessionFn);
+    hashSecret.grantRead(deleteSessionFn);
 
     return {
       getSessionFn,
       createSessionFn,
       deleteUserSessionsFn,
+      deleteSessionFn,
     };
   }
 }
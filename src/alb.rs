@@ -1,13 +1,42 @@
-use std::{collections::HashMap, future::Future};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    task::{Context, Poll},
+};
 
-use futures::{future::LocalBoxFuture, FutureExt};
-use http::{Method, Response, StatusCode};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use http::{header, HeaderValue, Method, Response, StatusCode};
 use lambda_http::{Request, RequestExt};
 use matchit::{InsertError, Router};
-use serde_json::json;
-use tracing::{info, debug};
+use tower::Service;
+use tracing::debug;
 
-use crate::utils::response;
+use self::extract::Handler;
+use crate::{
+    errors::AppError,
+    utils::{error_body_response, error_response, request_id, ErrorBody},
+};
+
+pub mod extract;
+pub mod middleware;
+
+/// the methods routes can be registered for, in the order they are listed in
+/// an `Allow` header.
+const METHODS: [Method; 9] = [
+    Method::CONNECT,
+    Method::DELETE,
+    Method::GET,
+    Method::HEAD,
+    Method::OPTIONS,
+    Method::PATCH,
+    Method::POST,
+    Method::PUT,
+    Method::TRACE,
+];
 
 pub type E = Box<dyn std::error::Error + Sync + Send + 'static>;
 
@@ -15,33 +44,146 @@ pub type HandlerResponse = Result<Response<String>, E>;
 
 /// represents for a functional ALB request handler.
 pub type RequestHandler = fn(Request) -> Result<Response<String>, E>;
-pub type BoxedHandler<'a> = Box<dyn Fn(Request) -> LocalBoxFuture<'a, HandlerResponse> + 'a>;
+pub type BoxedHandler =
+    Arc<dyn Fn(Request) -> BoxFuture<'static, HandlerResponse> + Send + Sync + 'static>;
+
+/// wraps the rest of a chain: it can alter the request, answer on its own or
+/// post-process the response returned by `next.run`.
+pub type Middleware =
+    Arc<dyn Fn(Request, Next) -> BoxFuture<'static, HandlerResponse> + Send + Sync + 'static>;
+
+/// boxes a function or closure into a `Middleware`.
+pub fn from_fn<F, Fut>(middleware: F) -> Middleware
+where
+    F: 'static + Send + Sync + (Fn(Request, Next) -> Fut),
+    Fut: 'static + Send + Future<Output = HandlerResponse>,
+{
+    Arc::new(move |request, next| middleware(request, next).boxed())
+}
+
+/// the remainder of a middleware chain, ending with the route handler.
+#[derive(Clone)]
+pub struct Next {
+    middlewares: Arc<[Middleware]>,
+    position: usize,
+    handler: BoxedHandler,
+}
+
+impl Next {
+    fn new(middlewares: Vec<Middleware>, handler: BoxedHandler) -> Self {
+        Next {
+            middlewares: middlewares.into(),
+            position: 0,
+            handler,
+        }
+    }
+
+    /// passes the request down to the next middleware, or to the handler once
+    /// the chain is exhausted.
+    pub fn run(self, request: Request) -> BoxFuture<'static, HandlerResponse> {
+        match self.middlewares.get(self.position).cloned() {
+            Some(middleware) => middleware(
+                request,
+                Next {
+                    position: self.position + 1,
+                    ..self
+                },
+            ),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// a handler along with the middlewares scoped to its route.
+#[derive(Clone)]
+struct Route {
+    handler: BoxedHandler,
+    middlewares: Vec<Middleware>,
+}
 
 /// specialises the matchit.Router to work with ALB lambda targets.
-pub struct AlbRouter<'b> {
-    routers: HashMap<Method, Router<BoxedHandler<'b>>>,
+///
+/// The router is cheap to clone and can be shared across threads: it is a
+/// `tower::Service`, so it can be handed to `lambda_http::run` as is, or to
+/// any server speaking `tower`. Handlers needing state capture it in an `Arc`.
+#[derive(Clone)]
+pub struct AlbRouter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// every registered route, to rebuild `routers` when cloning.
+    routes: Vec<(Method, String, Route)>,
+    routers: HashMap<Method, Router<Route>>,
+    middlewares: Vec<Middleware>,
     not_found: RequestHandler,
+    method_not_allowed: RequestHandler,
 }
 
-impl<'c> AlbRouter<'c> {
+impl Inner {
+    fn new(not_found: RequestHandler) -> Self {
+        let routers = METHODS
+            .iter()
+            .map(|method| (method.clone(), Router::new()))
+            .collect();
+
+        Inner {
+            routes: Vec::new(),
+            routers,
+            middlewares: Vec::new(),
+            not_found,
+            method_not_allowed,
+        }
+    }
+
+    fn insert(&mut self, method: Method, route: String, value: Route) -> Result<(), InsertError> {
+        let router = self
+            .routers
+            .get_mut(&method)
+            .ok_or(InsertError::UnnamedParam)?;
+
+        router.insert(route.clone(), value.clone())?;
+        self.routes.push((method, route, value));
+        Ok(())
+    }
+}
+
+impl Clone for Inner {
+    fn clone(&self) -> Self {
+        let mut inner = Inner::new(self.not_found);
+        inner.middlewares = self.middlewares.clone();
+        inner.method_not_allowed = self.method_not_allowed;
+        for (method, route, value) in &self.routes {
+            inner
+                .insert(method.clone(), route.clone(), value.clone())
+                .expect("routes were already validated");
+        }
+        inner
+    }
+}
+
+impl AlbRouter {
     pub fn new() -> Self {
         AlbRouter::new_with_default(not_found)
     }
 
     pub fn new_with_default(not_found: RequestHandler) -> Self {
-        let routers: HashMap<Method, Router<BoxedHandler>> = HashMap::from([
-            (Method::CONNECT, Router::new()),
-            (Method::DELETE, Router::new()),
-            (Method::GET, Router::new()),
-            (Method::HEAD, Router::new()),
-            (Method::OPTIONS, Router::new()),
-            (Method::PATCH, Router::new()),
-            (Method::POST, Router::new()),
-            (Method::PUT, Router::new()),
-            (Method::TRACE, Router::new()),
-        ]);
+        AlbRouter {
+            inner: Arc::new(Inner::new(not_found)),
+        }
+    }
 
-        AlbRouter { routers, not_found }
+    /// replaces the handler answering requests whose path exists for other
+    /// methods only. The router sets the `Allow` header on its response.
+    pub fn with_method_not_allowed(mut self, handler: RequestHandler) -> Self {
+        Arc::make_mut(&mut self.inner).method_not_allowed = handler;
+        self
+    }
+
+    /// registers a middleware run on every request, unmatched ones included.
+    /// Middlewares run in the order they are added, before route middlewares.
+    pub fn layer(&mut self, middleware: Middleware) {
+        Arc::make_mut(&mut self.inner).middlewares.push(middleware);
     }
 
     pub fn insert<F, Fut>(
@@ -51,59 +193,111 @@ impl<'c> AlbRouter<'c> {
         handler: F,
     ) -> Result<(), InsertError>
     where
-        F: 'c + (Fn(Request) -> Fut),
-        Fut: 'c + Future<Output = HandlerResponse>,
+        F: 'static + Send + Sync + (Fn(Request) -> Fut),
+        Fut: 'static + Send + Future<Output = HandlerResponse>,
     {
-        let router = self
-            .routers
-            .get_mut(&method)
-            .ok_or(InsertError::UnnamedParam)?;
+        self.insert_with_middleware(method, route, handler, Vec::new())
+    }
 
-        router.insert(
-            route,
-            Box::new(move |request| handler(request).boxed_local()),
+    /// like `insert`, wrapping the handler in `middlewares` for this route only.
+    pub fn insert_with_middleware<F, Fut>(
+        &mut self,
+        method: Method,
+        route: impl Into<String>,
+        handler: F,
+        middlewares: Vec<Middleware>,
+    ) -> Result<(), InsertError>
+    where
+        F: 'static + Send + Sync + (Fn(Request) -> Fut),
+        Fut: 'static + Send + Future<Output = HandlerResponse>,
+    {
+        Arc::make_mut(&mut self.inner).insert(
+            method,
+            route.into(),
+            Route {
+                handler: Arc::new(move |request| handler(request).boxed()),
+                middlewares,
+            },
         )
     }
 
+    /// like `insert`, for handlers taking extractors instead of the request,
+    /// see `extract`.
+    pub fn route<H, T>(
+        &mut self,
+        method: Method,
+        route: impl Into<String>,
+        handler: H,
+    ) -> Result<(), InsertError>
+    where
+        H: Handler<T>,
+    {
+        self.insert(method, route, move |request| handler.call(request))
+    }
+
+    /// mounts every route of `router` under `prefix`, e.g. `/v1`. Path
+    /// parameters are kept, and the global middlewares of `router` only wrap
+    /// its own routes. Its fallback handlers are dropped in favour of ours.
+    pub fn nest(&mut self, prefix: &str, router: AlbRouter) -> Result<(), InsertError> {
+        let prefix = prefix.trim_end_matches('/');
+        self.absorb(router, |route| match route {
+            "/" if !prefix.is_empty() => prefix.to_owned(),
+            _ => format!("{}{}", prefix, route),
+        })
+    }
+
+    /// adds every route of `router` as is, see `nest`.
+    pub fn merge(&mut self, router: AlbRouter) -> Result<(), InsertError> {
+        self.absorb(router, str::to_owned)
+    }
+
+    fn absorb(
+        &mut self,
+        router: AlbRouter,
+        path: impl Fn(&str) -> String,
+    ) -> Result<(), InsertError> {
+        let scoped = &router.inner.middlewares;
+        let inner = Arc::make_mut(&mut self.inner);
+        for (method, route, value) in &router.inner.routes {
+            let middlewares = scoped.iter().chain(&value.middlewares).cloned();
+            inner.insert(
+                method.clone(),
+                path(route.as_str()),
+                Route {
+                    handler: value.handler.clone(),
+                    middlewares: middlewares.collect(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
     pub async fn handle(&self, request: Request) -> HandlerResponse {
-        info!(
+        debug!(
             "uri: {}, raw_http_path: {}",
             request.uri(),
             request.raw_http_path()
         );
 
-        let not_found_handler = &self.not_found;
-        let router = match self.routers.get(request.method()) {
-            Some(router) => router,
-            None => return not_found_handler(request),
-        };
-
+        let request = with_raw_path(with_uri_query(request));
         let raw_path = request.raw_http_path();
-        let user_agent: String = request
-            .headers()
-            .get(http::header::USER_AGENT)
-            .map(|h| h.to_str().unwrap().to_owned())
-            .unwrap_or(String::from("N/A"));
-        let host: String = request
-            .headers()
-            .get(http::header::HOST)
-            .map(|h| h.to_str().unwrap().to_owned())
-            .unwrap_or(String::from("N/A"));
-            
-        info!(
-            "[{} {}] [{} {}]",
-            user_agent,
-            host,
-            request.method(),
-            raw_path
-        );
-        let matched = match router.at(&raw_path) {
-            Ok(matched) => matched,
-            Err(_) => return not_found_handler(request),
+        let matched = self
+            .inner
+            .routers
+            .get(request.method())
+            .and_then(|router| router.at(&raw_path).ok());
+        let matched = match matched {
+            Some(matched) => matched,
+            None => {
+                let fallback = self.fallback(&request, &raw_path);
+                return self
+                    .run(fallback.middlewares, fallback.handler, request)
+                    .await;
+            }
         };
 
         debug!("match found!");
-        let handler = matched.value;
+        let route = matched.value.clone();
 
         let iter = matched
             .params
@@ -115,23 +309,172 @@ impl<'c> AlbRouter<'c> {
         let event = request.with_path_parameters(params);
         debug!("event.params: {:?}", event.path_parameters());
 
-        handler(event).await
+        self.run(route.middlewares, route.handler, event).await
+    }
+
+    /// picks the handler for a request no route matched: `not_found` when no
+    /// method knows the path, else the list of allowed methods for `OPTIONS`
+    /// and `method_not_allowed` for anything else, both with an `Allow` header.
+    ///
+    /// `OPTIONS` runs through the middlewares of the route a preflight asks
+    /// about in `Access-Control-Request-Method`, or else of the first route of
+    /// the path, so that route-scoped CORS headers make it to the response.
+    fn fallback(&self, request: &Request, path: &str) -> Route {
+        let mut allowed: Vec<&str> = METHODS
+            .iter()
+            .filter(|m| self.inner.routers[*m].at(path).is_ok())
+            .map(Method::as_str)
+            .collect();
+        if allowed.is_empty() {
+            return Route {
+                handler: boxed(self.inner.not_found),
+                middlewares: Vec::new(),
+            };
+        }
+        if !allowed.contains(&Method::OPTIONS.as_str()) {
+            allowed.push(Method::OPTIONS.as_str());
+            allowed.sort_unstable();
+        }
+
+        let allow = HeaderValue::from_str(&allowed.join(", ")).expect("method names are tokens");
+        let (handler, middlewares) = match *request.method() {
+            Method::OPTIONS => (options as RequestHandler, self.preflighted(request, path)),
+            _ => (self.inner.method_not_allowed, Vec::new()),
+        };
+        Route {
+            handler: Arc::new(move |request| {
+                let res = handler(request).map(|mut res| {
+                    res.headers_mut()
+                        .entry(header::ALLOW)
+                        .or_insert_with(|| allow.clone());
+                    res
+                });
+                future::ready(res).boxed()
+            }),
+            middlewares,
+        }
+    }
+
+    /// the middlewares of the route an `OPTIONS` request on `path` is about.
+    fn preflighted(&self, request: &Request, path: &str) -> Vec<Middleware> {
+        let requested = request
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+
+        requested
+            .iter()
+            .chain(METHODS.iter())
+            .find_map(|method| self.inner.routers.get(method)?.at(path).ok())
+            .map(|matched| matched.value.middlewares.clone())
+            .unwrap_or_default()
+    }
+
+    /// runs the global middlewares, then `middlewares`, then `handler`.
+    fn run(
+        &self,
+        middlewares: Vec<Middleware>,
+        handler: BoxedHandler,
+        request: Request,
+    ) -> BoxFuture<'static, HandlerResponse> {
+        let chain = self
+            .inner
+            .middlewares
+            .iter()
+            .cloned()
+            .chain(middlewares)
+            .collect();
+        Next::new(chain, handler).run(request)
+    }
+}
+
+impl Default for AlbRouter {
+    fn default() -> Self {
+        AlbRouter::new()
+    }
+}
+
+impl Service<Request> for AlbRouter {
+    type Response = Response<String>;
+    type Error = E;
+    type Future = BoxFuture<'static, HandlerResponse>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let router = self.clone();
+        async move { router.handle(request).await }.boxed()
+    }
+}
+
+/// fills the query parameters from the URI when the event carries none, as for
+/// requests coming from a plain HTTP server rather than a Lambda event.
+fn with_uri_query(request: Request) -> Request {
+    let query = match request.uri().query() {
+        Some(query) if request.query_string_parameters().is_empty() => query,
+        _ => return request,
+    };
+    let pairs: Vec<(String, String)> = match serde_html_form::from_str(query) {
+        Ok(pairs) => pairs,
+        Err(err) => {
+            debug!("ignoring malformed query string: {}", err);
+            return request;
+        }
+    };
+
+    let mut params: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in pairs {
+        params.entry(key).or_default().push(value);
+    }
+    request.with_query_string_parameters(params)
+}
+
+/// the path of the URI, for requests not built from a Lambda event.
+fn with_raw_path(request: Request) -> Request {
+    if !request.raw_http_path().is_empty() {
+        return request;
     }
+    let path = request.uri().path().to_owned();
+    request.with_raw_http_path(&path)
+}
+
+fn boxed(handler: RequestHandler) -> BoxedHandler {
+    Arc::new(move |request| future::ready(handler(request)).boxed())
 }
 
 pub fn not_found(event: Request) -> Result<Response<String>, E> {
-    let body = json!({
-        "error": format!("endpoint {} not found", event.raw_http_path()),
-    })
-    .to_string();
-    Ok(response(StatusCode::NOT_FOUND, body))
+    let err = AppError::NotFound(format!("endpoint {} not found", event.raw_http_path()));
+    Ok(error_response(&event, &err))
+}
+
+pub fn method_not_allowed(event: Request) -> Result<Response<String>, E> {
+    let body = ErrorBody::new(
+        "method_not_allowed",
+        &format!(
+            "method {} not allowed on {}",
+            event.method(),
+            event.raw_http_path()
+        ),
+    )
+    .with_request_id(request_id(&event));
+    Ok(error_body_response(StatusCode::METHOD_NOT_ALLOWED, &body))
+}
+
+/// answers `OPTIONS` for paths without an explicit `OPTIONS` route.
+fn options(_: Request) -> Result<Response<String>, E> {
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(String::new())?)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
 
     use super::*;
+    use crate::utils::response;
 
     async fn dummy_handler_a(_: Request) -> HandlerResponse {
         Ok(response(StatusCode::NOT_FOUND, String::from("{}")))
@@ -150,6 +493,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::redundant_closure)] // closures are the point.
     fn router_should_accept_closures() {
         let mut router = AlbRouter::new();
         let res_a = router.insert(Method::GET, "/tests", |req| dummy_handler_a(req));
@@ -157,9 +501,279 @@ mod tests {
         assert!(res_a.is_ok() && res_b.is_ok());
     }
 
+    #[tokio::test]
+    async fn unknown_routes_get_an_error_body() {
+        let router = AlbRouter::new();
+
+        let res = router.handle(get("/nope")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_str(res.body()).unwrap();
+        assert_eq!(body["code"], "not_found");
+    }
+
+    fn request(method: Method, uri: &str) -> Request {
+        http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(lambda_http::Body::Empty)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn other_methods_get_a_405_with_allow() {
+        let mut router = AlbRouter::new();
+        router.insert(Method::GET, "/wow", dummy_handler_b).unwrap();
+        router
+            .insert(Method::DELETE, "/wow", dummy_handler_b)
+            .unwrap();
+
+        let res = router.handle(request(Method::PUT, "/wow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[header::ALLOW], "DELETE, GET, OPTIONS");
+        let body: serde_json::Value = serde_json::from_str(res.body()).unwrap();
+        assert_eq!(body["code"], "method_not_allowed");
+
+        let res = router.handle(request(Method::PUT, "/nope")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_allowed_methods() {
+        let mut router = AlbRouter::new();
+        router
+            .insert(Method::POST, "/sessions/:id", dummy_handler_b)
+            .unwrap();
+        router
+            .insert(Method::OPTIONS, "/custom", dummy_handler_a)
+            .unwrap();
+
+        let res = router
+            .handle(request(Method::OPTIONS, "/sessions/abc"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[header::ALLOW], "OPTIONS, POST");
+
+        // explicit OPTIONS routes win.
+        let res = router
+            .handle(request(Method::OPTIONS, "/custom"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn options_runs_the_middlewares_of_the_route() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut router = AlbRouter::new();
+        router
+            .insert_with_middleware(
+                Method::GET,
+                "/wow",
+                dummy_handler_b,
+                vec![record("get", calls.clone())],
+            )
+            .unwrap();
+        router
+            .insert_with_middleware(
+                Method::POST,
+                "/wow",
+                dummy_handler_b,
+                vec![
+                    record("post", calls.clone()),
+                    middleware::cors("https://example.com").unwrap(),
+                ],
+            )
+            .unwrap();
+
+        let mut preflight = request(Method::OPTIONS, "/wow");
+        preflight.headers_mut().insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("POST"),
+        );
+        let res = router.handle(preflight).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(res.headers()[header::ALLOW], "GET, OPTIONS, POST");
+        assert_eq!(*calls.lock().unwrap(), vec!["> post", "< post"]);
+
+        calls.lock().unwrap().clear();
+        router
+            .handle(request(Method::OPTIONS, "/wow"))
+            .await
+            .unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["> get", "< get"]);
+    }
+
+    fn teapot(_: Request) -> Result<Response<String>, E> {
+        Ok(response(StatusCode::IM_A_TEAPOT, String::from("{}")))
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed_is_customisable() {
+        let mut router = AlbRouter::new().with_method_not_allowed(teapot);
+        router.insert(Method::GET, "/wow", dummy_handler_b).unwrap();
+
+        let res = router.handle(request(Method::POST, "/wow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(res.headers()[header::ALLOW], "GET, OPTIONS");
+    }
+
+    fn get(uri: &str) -> Request {
+        http::Request::builder()
+            .uri(uri)
+            .body(lambda_http::Body::Empty)
+            .unwrap()
+    }
+
+    /// records its name on the way in and on the way out.
+    fn record(name: &'static str, calls: Arc<Mutex<Vec<String>>>) -> Middleware {
+        from_fn(move |request: Request, next: Next| {
+            let calls = calls.clone();
+            async move {
+                calls.lock().unwrap().push(format!("> {}", name));
+                let res = next.run(request).await;
+                calls.lock().unwrap().push(format!("< {}", name));
+                res
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn middlewares_wrap_handlers_in_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut router = AlbRouter::new();
+        router.layer(record("global", calls.clone()));
+        router
+            .insert_with_middleware(
+                Method::GET,
+                "/scoped",
+                dummy_handler_b,
+                vec![record("route", calls.clone())],
+            )
+            .unwrap();
+        router
+            .insert(Method::GET, "/plain", dummy_handler_b)
+            .unwrap();
+
+        router.handle(get("/scoped")).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["> global", "> route", "< route", "< global"]
+        );
+
+        calls.lock().unwrap().clear();
+        router.handle(get("/plain")).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["> global", "< global"]);
+
+        calls.lock().unwrap().clear();
+        router.handle(get("/missing")).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["> global", "< global"]);
+    }
+
+    async fn forbid(_: Request, _: Next) -> HandlerResponse {
+        Ok(response(StatusCode::FORBIDDEN, String::from("{}")))
+    }
+
+    #[tokio::test]
+    async fn middlewares_can_answer_on_their_own() {
+        let mut router = AlbRouter::new();
+        router.layer(from_fn(forbid));
+        router.insert(Method::GET, "/wow", dummy_handler_b).unwrap();
+
+        let res = router.handle(get("/wow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    async fn echo_id(request: Request) -> HandlerResponse {
+        let id = request
+            .path_parameters()
+            .first("id")
+            .unwrap_or("")
+            .to_owned();
+        Ok(response(StatusCode::OK, id))
+    }
+
+    #[tokio::test]
+    async fn nested_routers_keep_params_and_their_middlewares() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut v1 = AlbRouter::new();
+        v1.layer(record("v1", calls.clone()));
+        v1.insert(Method::GET, "/", dummy_handler_b).unwrap();
+        v1.insert(Method::GET, "/sessions/:id", echo_id).unwrap();
+
+        let mut router = AlbRouter::new();
+        router.layer(record("global", calls.clone()));
+        router.insert(Method::GET, "/", dummy_handler_b).unwrap();
+        router.nest("/v1/", v1).unwrap();
+
+        let res = router.handle(get("/v1/sessions/abc")).await.unwrap();
+        assert_eq!(res.body(), "abc");
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["> global", "> v1", "< v1", "< global"]
+        );
+
+        calls.lock().unwrap().clear();
+        let res = router.handle(get("/v1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        router.handle(get("/")).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["> global", "> v1", "< v1", "< global", "> global", "< global"]
+        );
+    }
+
+    #[tokio::test]
+    async fn merged_routers_share_the_path_space() {
+        let mut users = AlbRouter::new();
+        users.insert(Method::GET, "/users/:id", echo_id).unwrap();
+
+        let mut router = AlbRouter::new();
+        router.insert(Method::GET, "/wow", dummy_handler_b).unwrap();
+        router.merge(users).unwrap();
+
+        let res = router.handle(get("/users/bob")).await.unwrap();
+        assert_eq!(res.body(), "bob");
+
+        let mut clash = AlbRouter::new();
+        clash.insert(Method::GET, "/wow", dummy_handler_a).unwrap();
+        assert!(router.merge(clash).is_err());
+    }
+
+    #[test]
+    fn router_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<AlbRouter>();
+    }
+
+    #[tokio::test]
+    async fn router_is_a_tower_service() {
+        let mut router = AlbRouter::new();
+        router.insert(Method::GET, "/wow", dummy_handler_b).unwrap();
+
+        let mut service = router.clone();
+        // the clone keeps working once the original router changes.
+        router
+            .insert(Method::GET, "/tests", dummy_handler_a)
+            .unwrap();
+
+        let res = tokio::spawn(service.call(get("/wow")))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = service.call(get("/tests")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.body().contains("not_found"));
+    }
+
     #[test]
     fn router_should_accept_closure_with_capture() {
-        let map = Rc::new(HashMap::from([("key1", 1), ("key2", 2)]));
+        let map = Arc::new(HashMap::from([("key1", 1), ("key2", 2)]));
 
         let mut router = AlbRouter::new();
 
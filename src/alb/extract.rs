@@ -0,0 +1,563 @@
+//! # Typed extractors for `AlbRouter` handlers.
+//!
+//! Handlers registered with `AlbRouter::route` take their inputs as arguments
+//! instead of the raw request, every argument implementing `FromRequest`:
+//!
+//! ```ignore
+//! async fn put_data(
+//!     BearerToken(token): BearerToken,
+//!     Json(data): Json<SessionData>,
+//! ) -> Result<Response<String>, Rejection> {
+//!     ...
+//! }
+//!
+//! router.route(Method::PUT, "/sessions/data", put_data)?;
+//! ```
+//!
+//! Extractors run in argument order. The first one to fail answers the request
+//! with its `Rejection`, and the handler is not called.
+
+use std::{any, future::Future};
+
+use async_trait::async_trait;
+use futures::{future::BoxFuture, FutureExt};
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use lambda_http::{request::RequestContext, Request, RequestExt, Response};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tracing::warn;
+
+use super::HandlerResponse;
+use crate::{
+    errors::AppError,
+    utils::{error_body_response, request_id, ErrorBody},
+};
+
+/// an error response: the failure of an extractor, or of a handler.
+#[derive(Debug)]
+pub struct Rejection {
+    status_code: StatusCode,
+    headers: HeaderMap,
+    /// boxed to keep handler results small.
+    body: Box<ErrorBody>,
+}
+
+impl Rejection {
+    pub fn new(status_code: StatusCode, body: ErrorBody) -> Self {
+        Rejection {
+            status_code,
+            headers: HeaderMap::new(),
+            body: Box::new(body),
+        }
+    }
+
+    /// a 401 for a bearer token that is malformed, unknown or expired.
+    pub fn invalid_token(err: AppError) -> Self {
+        Rejection::from(err).with_header(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Bearer error="invalid_token""#),
+        )
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// serializes the rejection, tagged with the id of `request`.
+    pub fn into_response(self, request: &Request) -> Response<String> {
+        let body = match self.body.request_id {
+            Some(_) => *self.body,
+            None => self.body.with_request_id(request_id(request)),
+        };
+        let mut res = error_body_response(self.status_code, &body);
+        res.headers_mut().extend(self.headers);
+        res
+    }
+}
+
+impl From<AppError> for Rejection {
+    /// 401s challenge the client for a bearer token, as RFC 7235 requires.
+    fn from(err: AppError) -> Self {
+        let rejection = Rejection::new(err.status_code(), ErrorBody::from(&err));
+        match err.status_code() {
+            StatusCode::UNAUTHORIZED => {
+                rejection.with_header(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))
+            }
+            _ => rejection,
+        }
+    }
+}
+
+/// what handlers taking extractors return.
+pub type ApiResponse = Result<Response<String>, Rejection>;
+
+/// a value resolved from the request before the handler runs.
+#[async_trait]
+pub trait FromRequest: Sized {
+    async fn from_request(request: &Request) -> Result<Self, Rejection>;
+}
+
+/// an async function whose arguments are all extractors. `T` only tells the
+/// implementations for each arity apart.
+pub trait Handler<T>: Clone + Send + Sync + 'static {
+    fn call(&self, request: Request) -> BoxFuture<'static, HandlerResponse>;
+}
+
+macro_rules! impl_handler {
+    ($($ty:ident $arg:ident),*) => {
+        impl<F, Fut, $($ty,)*> Handler<($($ty,)*)> for F
+        where
+            F: Fn($($ty),*) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future<Output = ApiResponse> + Send + 'static,
+            $($ty: FromRequest + Send + 'static,)*
+        {
+            fn call(&self, request: Request) -> BoxFuture<'static, HandlerResponse> {
+                let handler = self.clone();
+                async move {
+                    $(
+                        let $arg = match <$ty as FromRequest>::from_request(&request).await {
+                            Ok(value) => value,
+                            Err(rejection) => return Ok(rejection.into_response(&request)),
+                        };
+                    )*
+                    match handler($($arg),*).await {
+                        Ok(res) => Ok(res),
+                        Err(rejection) => Ok(rejection.into_response(&request)),
+                    }
+                }
+                .boxed()
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(T1 t1);
+impl_handler!(T1 t1, T2 t2);
+impl_handler!(T1 t1, T2 t2, T3 t3);
+impl_handler!(T1 t1, T2 t2, T3 t3, T4 t4);
+impl_handler!(T1 t1, T2 t2, T3 t3, T4 t4, T5 t5);
+
+/// the token of an `Authorization: Bearer <token>` header, as defined by
+/// RFC 6750: the scheme is case-insensitive, the token is kept as sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BearerToken(pub String);
+
+impl BearerToken {
+    /// reads the bearer token of `request`, `None` when it sends no
+    /// `Authorization` header at all.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<BearerToken>, Rejection> {
+        let value = match headers.get(header::AUTHORIZATION) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let token = value.to_str().ok().and_then(|value| {
+            let (scheme, token) = value.split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("bearer") {
+                return None;
+            }
+            Some(token.trim_start_matches(' '))
+        });
+        match token {
+            Some(token) if is_b64token(token) => Ok(Some(BearerToken(token.to_owned()))),
+            _ => Err(Rejection::invalid_token(AppError::Unauthorized(
+                "malformed bearer token".to_owned(),
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequest for BearerToken {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        match BearerToken::from_headers(request.headers())? {
+            Some(token) => Ok(token),
+            None => Err(AppError::Unauthorized("Missing Header: Authorization".to_owned()).into()),
+        }
+    }
+}
+
+/// `b64token = 1*( ALPHA / DIGIT / "-" / "." / "_" / "~" / "+" / "/" ) *"="`
+fn is_b64token(token: &str) -> bool {
+    let token = token.trim_end_matches('=');
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~+/".contains(&b))
+}
+
+/// the value of the cookie `name`, if the request carries one.
+pub fn cookie(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"').to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+/// a JSON request body, sent with an `application/json` content type.
+#[derive(Debug, Clone)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Send> FromRequest for Json<T> {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        let is_json_content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|ct| ct.to_str().unwrap_or("").starts_with("application/json"))
+            .unwrap_or(false);
+
+        if !is_json_content_type {
+            return Err(AppError::Validation("expects JSON payload".to_owned()).into());
+        }
+
+        serde_json::from_slice(request.body())
+            .map(Json)
+            .map_err(|err| {
+                warn!("{}", err);
+                invalid("invalid payload, cannot parse JSON", err)
+            })
+    }
+}
+
+/// the parameters of the matched route, e.g. `username` for
+/// `/users/:username/sessions`, deserialized into a struct.
+#[derive(Debug, Clone)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Send> FromRequest for Path<T> {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        let params = request.path_parameters();
+        from_pairs(params.iter(), false)
+            .map(Path)
+            .map_err(|err| invalid("invalid path parameters", err))
+    }
+}
+
+/// the query string, deserialized into a struct. Repeated keys, such as the
+/// ALB `multiValueQueryStringParameters`, fill `Vec` fields:
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct Filter {
+///     status: Vec<String>,
+///     limit: Option<usize>,
+/// }
+///
+/// // ?status=active&status=expired&limit=10
+/// async fn list(Query(filter): Query<Filter>) -> ApiResponse { ... }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Send> FromRequest for Query<T> {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        // the load balancer passes query parameters on without decoding them.
+        let encoded = matches!(
+            request.extensions().get::<RequestContext>(),
+            Some(RequestContext::Alb(_))
+        );
+        let params = request.query_string_parameters();
+        from_pairs(params.iter(), encoded)
+            .map(Query)
+            .map_err(|err| invalid("invalid query parameters", err))
+    }
+}
+
+/// a value shared with handlers through the request extensions, typically
+/// inserted by `middleware::extension`.
+#[derive(Debug, Clone)]
+pub struct Extension<T>(pub T);
+
+#[async_trait]
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        match request.extensions().get::<T>() {
+            Some(value) => Ok(Extension(value.clone())),
+            None => Err(AppError::backend(format!(
+                "missing request extension {}",
+                any::type_name::<T>()
+            ))
+            .into()),
+        }
+    }
+}
+
+/// deserializes string pairs the way a form would be, so that numbers and
+/// booleans parse from their text. `encoded` pairs are still percent-encoded.
+fn from_pairs<'a, T: DeserializeOwned>(
+    pairs: impl Iterator<Item = (&'a str, &'a str)>,
+    encoded: bool,
+) -> Result<T, serde_html_form::de::Error> {
+    let form = if encoded {
+        pairs
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&")
+    } else {
+        let pairs: Vec<_> = pairs.collect();
+        serde_html_form::to_string(pairs).expect("string pairs always encode")
+    };
+    serde_html_form::from_str(&form)
+}
+
+fn invalid(message: &str, err: impl std::fmt::Display) -> Rejection {
+    let invalid = AppError::Validation(message.to_owned());
+    Rejection::new(
+        invalid.status_code(),
+        ErrorBody::from(&invalid).with_details(json!({ "reason": err.to_string() })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use http::Method;
+    use lambda_http::Body;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        alb::{middleware, AlbRouter},
+        utils::response,
+    };
+
+    #[derive(Debug, Deserialize)]
+    struct Page {
+        limit: Option<usize>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Filter {
+        #[serde(default)]
+        status: Vec<String>,
+        q: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct UserPath {
+        username: String,
+    }
+
+    async fn echo(
+        Extension(prefix): Extension<Arc<String>>,
+        BearerToken(token): BearerToken,
+        Path(path): Path<UserPath>,
+        Query(page): Query<Page>,
+    ) -> ApiResponse {
+        let body = format!("{}{} {} {:?}", prefix, token, path.username, page.limit);
+        Ok(response(StatusCode::OK, body))
+    }
+
+    async fn json_len(Json(values): Json<Vec<u32>>) -> ApiResponse {
+        Ok(response(StatusCode::OK, values.len().to_string()))
+    }
+
+    async fn filter(Query(filter): Query<Filter>) -> ApiResponse {
+        let body = format!("{:?} {:?}", filter.status, filter.q);
+        Ok(response(StatusCode::OK, body))
+    }
+
+    async fn conflict() -> ApiResponse {
+        Err(AppError::Conflict("raced".to_owned()).into())
+    }
+
+    fn router() -> AlbRouter {
+        let mut router = AlbRouter::new();
+        router.layer(middleware::extension(Arc::new("> ".to_owned())));
+        router.route(Method::GET, "/users/:username", echo).unwrap();
+        router.route(Method::POST, "/json", json_len).unwrap();
+        router.route(Method::GET, "/conflict", conflict).unwrap();
+        router.route(Method::GET, "/filter", filter).unwrap();
+        router
+    }
+
+    fn code(res: &Response<String>) -> String {
+        let body: serde_json::Value = serde_json::from_str(res.body()).unwrap();
+        body["code"].as_str().unwrap().to_owned()
+    }
+
+    fn get(uri: &str, query: &[(&str, &str)], token: Option<&str>) -> Request {
+        let mut request = http::Request::builder().uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request
+            .body(Body::Empty)
+            .unwrap()
+            .with_query_string_parameters(
+                query
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+                    .collect::<HashMap<String, Vec<String>>>(),
+            )
+    }
+
+    #[tokio::test]
+    async fn extractors_feed_the_handler() {
+        let res = router()
+            .handle(get("/users/alice", &[("limit", "5")], Some("abc")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "> abc alice Some(5)");
+    }
+
+    #[tokio::test]
+    async fn missing_token_is_a_401() {
+        let res = router()
+            .handle(get("/users/alice", &[], None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(code(&res), "unauthorized");
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn bearer_tokens_follow_rfc_6750() {
+        let parse = |value: &str| BearerToken::from_headers(&authorization(value));
+
+        let token = BearerToken("AbC-12.x~+/==".to_owned());
+        assert_eq!(parse("Bearer AbC-12.x~+/==").unwrap(), Some(token.clone()));
+        assert_eq!(parse("bEaReR AbC-12.x~+/==").unwrap(), Some(token));
+        assert_eq!(BearerToken::from_headers(&HeaderMap::new()).unwrap(), None);
+
+        for value in [
+            "xxbearer yy",
+            "Basic YWxpY2U6cGluZ3Bvbmc=",
+            "Bearer",
+            "Bearer ",
+            "Bearer a b",
+            "Bearer =abc",
+            "Bearer ab=c",
+        ] {
+            assert!(parse(value).is_err(), "{} should be rejected", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn unauthorized_responses_carry_a_challenge() {
+        let res = router()
+            .handle(get("/users/alice", &[], None))
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let mut request = get("/users/alice", &[], None);
+        request.headers_mut().insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("xxbearer yy"),
+        );
+        let res = router().handle(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer error="invalid_token""#
+        );
+    }
+
+    #[test]
+    fn cookies_are_found_by_name() {
+        let request = http::Request::builder()
+            .header(header::COOKIE, "theme=dark; session=abc")
+            .header(header::COOKIE, "other=1")
+            .body(Body::Empty)
+            .unwrap();
+
+        assert_eq!(cookie(&request, "session").as_deref(), Some("abc"));
+        assert_eq!(cookie(&request, "other").as_deref(), Some("1"));
+        assert_eq!(cookie(&request, "sess"), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_query_is_a_400() {
+        let res = router()
+            .handle(get("/users/alice", &[("limit", "many")], Some("abc")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(code(&res), "validation_error");
+    }
+
+    #[tokio::test]
+    async fn repeated_query_keys_fill_vectors() {
+        let request = get("/filter", &[], None).with_query_string_parameters(HashMap::from([
+            (
+                "status".to_owned(),
+                vec!["active".to_owned(), "expired".to_owned()],
+            ),
+            ("q".to_owned(), vec!["a b&c".to_owned()]),
+        ]));
+        let res = router().handle(request).await.unwrap();
+        assert_eq!(res.body(), r#"["active", "expired"] Some("a b&c")"#);
+
+        let res = router().handle(get("/filter", &[], None)).await.unwrap();
+        assert_eq!(res.body(), "[] None");
+    }
+
+    #[tokio::test]
+    async fn query_strings_are_read_from_the_uri() {
+        let request = get(
+            "/filter?status=active&q=a%20b%26c&status=expired",
+            &[],
+            None,
+        );
+        let res = router().handle(request).await.unwrap();
+        assert_eq!(res.body(), r#"["active", "expired"] Some("a b&c")"#);
+    }
+
+    #[tokio::test]
+    async fn json_bodies_are_checked() {
+        let post = |content_type: &str, body: &str| {
+            http::Request::builder()
+                .method(Method::POST)
+                .uri("/json")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let res = router()
+            .handle(post("application/json", "[1, 2, 3]"))
+            .await
+            .unwrap();
+        assert_eq!(res.body(), "3");
+
+        let res = router()
+            .handle(post("text/plain", "[1, 2, 3]"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = router()
+            .handle(post("application/json", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.body().contains("reason"));
+    }
+
+    #[tokio::test]
+    async fn handler_errors_become_responses() {
+        let res = router().handle(get("/conflict", &[], None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(code(&res), "conflict");
+    }
+}
@@ -0,0 +1,244 @@
+//! # Reusable middlewares for `AlbRouter`.
+//!
+//! Plain middlewares are `async fn`s to wrap with `from_fn`, configurable ones
+//! build their `Middleware` themselves:
+//!
+//! ```ignore
+//! router.layer(from_fn(middleware::trace));
+//! router.layer(middleware::cors("https://example.com")?);
+//! ```
+
+use std::time::Instant;
+
+use http::{header, HeaderValue, StatusCode};
+use lambda_http::{Request, RequestExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::{from_fn, HandlerResponse, Middleware, Next};
+use crate::{
+    errors::AppError,
+    utils::{error_body_response, request_id, ErrorBody},
+};
+
+const REQUEST_ID: &str = "x-request-id";
+
+/// logs every request with its client, then its status and duration.
+pub async fn trace(request: Request, next: Next) -> HandlerResponse {
+    let header_value = |name: header::HeaderName| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("N/A")
+            .to_owned()
+    };
+    let user_agent = header_value(header::USER_AGENT);
+    let host = header_value(header::HOST);
+    let method = request.method().clone();
+    let raw_path = request.raw_http_path().to_owned();
+    info!("[{} {}] [{} {}]", user_agent, host, method, raw_path);
+
+    let started = Instant::now();
+    let res = next.run(request).await;
+    let elapsed = started.elapsed().as_millis();
+    match &res {
+        Ok(response) => info!(
+            "[{} {}] {} in {}ms",
+            method,
+            raw_path,
+            response.status(),
+            elapsed
+        ),
+        Err(err) => warn!("[{} {}] failed in {}ms: {}", method, raw_path, elapsed, err),
+    }
+
+    res
+}
+
+/// tags the request and its response with an `x-request-id`, reusing the
+/// invocation id when there is one.
+pub async fn request_ids(mut request: Request, next: Next) -> HandlerResponse {
+    let id = request_id(&request).unwrap_or_else(|| Uuid::new_v4().to_string());
+    let value = HeaderValue::from_str(&id)?;
+    request.headers_mut().insert(REQUEST_ID, value.clone());
+
+    let mut res = next.run(request).await?;
+    res.headers_mut().insert(REQUEST_ID, value);
+    Ok(res)
+}
+
+/// turns handler errors into error responses, so that the caller gets a JSON
+/// body instead of a bare failure of the Lambda invocation.
+pub async fn map_errors(request: Request, next: Next) -> HandlerResponse {
+    let request_id = request_id(&request);
+    let err = match next.run(request).await {
+        Ok(res) => return Ok(res),
+        Err(err) => err,
+    };
+
+    warn!("handler failed: {}", err);
+    let (status_code, body) = match err.downcast_ref::<AppError>() {
+        Some(err) => (err.status_code(), ErrorBody::from(err)),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorBody::new("backend_error", "internal error"),
+        ),
+    };
+    Ok(error_body_response(
+        status_code,
+        &body.with_request_id(request_id),
+    ))
+}
+
+/// shares `value` with the handlers it wraps, see `extract::Extension`.
+pub fn extension<T: Clone + Send + Sync + 'static>(value: T) -> Middleware {
+    from_fn(move |mut request: Request, next: Next| {
+        request.extensions_mut().insert(value.clone());
+        next.run(request)
+    })
+}
+
+/// allows browsers on `allow_origin` to call the routes it wraps.
+///
+/// preflights are answered with the methods of the `Allow` header that
+/// `OPTIONS` responses carry, see `AlbRouter::handle`.
+pub fn cors(allow_origin: &str) -> Result<Middleware, header::InvalidHeaderValue> {
+    let allow_origin = HeaderValue::from_str(allow_origin)?;
+
+    Ok(from_fn(move |request: Request, next: Next| {
+        let allow_origin = allow_origin.clone();
+        async move {
+            let mut res = next.run(request).await?;
+            let headers = res.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static("authorization, content-type"),
+            );
+            if let Some(allow) = headers.get(header::ALLOW).cloned() {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow);
+            }
+            headers.insert(header::VARY, HeaderValue::from_static("origin"));
+            Ok(res)
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+    use lambda_http::Body;
+
+    use super::*;
+    use crate::{alb::AlbRouter, utils::response};
+
+    fn request(uri: &str) -> Request {
+        http::Request::builder().uri(uri).body(Body::Empty).unwrap()
+    }
+
+    async fn ok(_: Request) -> HandlerResponse {
+        Ok(response(StatusCode::OK, "{}".to_owned()))
+    }
+
+    async fn echo_request_id(request: Request) -> HandlerResponse {
+        let id = request.headers()[REQUEST_ID].to_str()?.to_owned();
+        Ok(response(StatusCode::OK, id))
+    }
+
+    async fn conflict(_: Request) -> HandlerResponse {
+        Err(AppError::Conflict("raced".to_owned()).into())
+    }
+
+    async fn opaque_failure(_: Request) -> HandlerResponse {
+        Err("secret details".into())
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_propagated() {
+        let mut router = AlbRouter::new();
+        router.layer(from_fn(request_ids));
+        router
+            .insert(Method::GET, "/echo", echo_request_id)
+            .unwrap();
+
+        let res = router.handle(request("/echo")).await.unwrap();
+        let header = res.headers().get(REQUEST_ID).unwrap().to_str().unwrap();
+        assert_eq!(header, res.body());
+        assert!(!header.is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_become_error_bodies() {
+        let mut router = AlbRouter::new();
+        router.layer(from_fn(map_errors));
+        router.insert(Method::GET, "/conflict", conflict).unwrap();
+        router
+            .insert(Method::GET, "/opaque", opaque_failure)
+            .unwrap();
+
+        let res = router.handle(request("/conflict")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert!(res.body().contains("\"code\":\"conflict\""));
+
+        let res = router.handle(request("/opaque")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!res.body().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn cors_headers_are_added() {
+        let mut router = AlbRouter::new();
+        router
+            .insert_with_middleware(
+                Method::GET,
+                "/cors",
+                ok,
+                vec![cors("https://example.com").unwrap()],
+            )
+            .unwrap();
+
+        let res = router.handle(request("/cors")).await.unwrap();
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_list_methods_and_headers() {
+        let mut router = AlbRouter::new();
+        for method in [Method::GET, Method::DELETE] {
+            router
+                .insert_with_middleware(
+                    method,
+                    "/cors",
+                    ok,
+                    vec![cors("https://example.com").unwrap()],
+                )
+                .unwrap();
+        }
+
+        let preflight = http::Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/cors")
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+            .body(Body::Empty)
+            .unwrap();
+        let res = router.handle(preflight).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "DELETE, GET, OPTIONS"
+        );
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization, content-type"
+        );
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+    }
+}
@@ -1,208 +1,1320 @@
-use std::{collections::HashSet};
+use std::sync::Arc;
 
+use crate::alb::extract::{
+    cookie, ApiResponse, BearerToken, Extension, FromRequest, Json, Path, Query, Rejection,
+};
+use crate::alb::{middleware, AlbRouter};
+use crate::backend::SessionBackend;
+use crate::config::SameSite;
+use crate::credentials::CredentialVerifier;
 use crate::errors::AppError;
-use crate::utils::{response,  internal_server_error};
-use crate::store::{SessionStore};
-use http::StatusCode;
-use lambda_http::{Request, RequestExt, Response};
-use lazy_static::lazy_static;
+use crate::jwt::JwtClaims;
+use crate::store::{
+    ClientMetadata, Session, SessionData, SessionLookup, SessionStore, SessionTokens,
+};
+use crate::utils::{response, ErrorBody};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use http::{HeaderValue, Method, StatusCode};
+use lambda_http::{Request, Response};
+use matchit::InsertError;
 use serde::Deserialize;
 use serde_json::json;
-use tracing::{info, instrument, warn};
-
-type E = Box<dyn std::error::Error + Sync + Send + 'static>;
-
-lazy_static! {
-    static ref PASSWORDS: HashSet<String> = HashSet::from([
-        "pingpong".to_owned(),
-        "moultipass".to_owned(),
-        "devoid of meaning".to_owned(),
-        "perlimpinpin".to_owned()
-    ]);
-}
-
-#[instrument(skip(store))]
-pub async fn create_session(
-    store: &SessionStore<'_>,
-    event: Request,
-) -> Result<Response<String>, E> {
-    let is_json_content_type = event
-        .headers()
-        .get(http::header::CONTENT_TYPE)
-        .map(|ct| ct.to_str().unwrap_or("").starts_with("application/json"))
-        .unwrap_or(false);
-
-    if !is_json_content_type {
-        return Ok(response(
-            StatusCode::BAD_REQUEST,
-            json!({ "error": "expects JSON payload" }).to_string(),
-        ));
-    }
-
-    let req = match serde_json::from_slice::<CreateSessionRequest>(event.body()) {
-        Ok(payload) => payload,
-        Err(err) => {
-            warn!("{}", err.to_string());
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({ "error": "invalid payload, cannot parse JSON" }).to_string(),
-            ));
-        }
-    };
+use tracing::{instrument, warn};
+
+/// routes every endpoint of the service, sharing `store` and `verifier`
+/// between requests.
+pub fn router<B: SessionBackend + 'static>(
+    store: Arc<SessionStore<B>>,
+    verifier: Arc<dyn CredentialVerifier>,
+) -> Result<AlbRouter, InsertError> {
+    let mut router = AlbRouter::new();
+    router.layer(middleware::extension(store));
+    router.layer(middleware::extension(verifier));
+    router.route(Method::GET, "/", health_check)?;
+    router.route(Method::GET, "/sessions", get_session::<B>)?;
+    router.route(Method::POST, "/sessions", create_session::<B>)?;
+    router.route(Method::DELETE, "/sessions", delete_session::<B>)?;
+    // formerly `DELETE /sessions/:username`, now `/users/:username/sessions`.
+    router.route(Method::DELETE, "/sessions/:id", delete_session::<B>)?;
+    router.route(Method::GET, "/sessions/data", get_session_data::<B>)?;
+    router.route(Method::PUT, "/sessions/data", put_session_data::<B>)?;
+    router.route(Method::PATCH, "/sessions/data", patch_session_data::<B>)?;
+    router.route(Method::POST, "/sessions/token", create_session_token::<B>)?;
+    router.route(Method::POST, "/sessions/refresh", refresh_session::<B>)?;
+    router.route(Method::GET, "/.well-known/jwks.json", jwks::<B>)?;
+    router.route(
+        Method::GET,
+        "/users/:username/sessions",
+        list_user_sessions::<B>,
+    )?;
+    router.route(
+        Method::DELETE,
+        "/users/:username/sessions",
+        delete_user_sessions::<B>,
+    )?;
+    Ok(router)
+}
+
+#[instrument(skip_all)]
+pub async fn create_session<B: SessionBackend + 'static>(
+    Extension(store): Extension<Arc<SessionStore<B>>>,
+    Extension(verifier): Extension<Arc<dyn CredentialVerifier>>,
+    client: ClientMetadata,
+    Json(req): Json<CreateSessionRequest>,
+) -> ApiResponse {
+    let user = verifier
+        .verify(&req.username, &req.password)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("incorrect password or username".to_owned()))?;
+
+    let tokens = store.create(req.username, user.roles, client).await?;
+    issued(&store, tokens)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSessionRequest {
+    username: String,
+    password: String,
+}
+
+/// trades a refresh token for a new session and refresh token, revoking the
+/// session it renewed. A refresh token used twice revokes its whole family.
+#[instrument(skip_all)]
+pub async fn refresh_session<B: SessionBackend + 'static>(
+    Extension(store): Extension<Arc<SessionStore<B>>>,
+    client: ClientMetadata,
+    Json(req): Json<RefreshSessionRequest>,
+) -> ApiResponse {
+    let tokens = store.refresh(req.refresh_token, client).await?;
+    issued(&store, tokens)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshSessionRequest {
+    refresh_token: String,
+}
 
-    if !PASSWORDS.contains(&req.password) {
-        return Ok(response(
-            StatusCode::UNAUTHORIZED,
-            json!({ "error": "incorrect password or username" }).to_string(),
-        ));
+/// answers with the tokens of a new session, also set as the session cookie.
+fn issued<B: SessionBackend>(store: &SessionStore<B>, tokens: SessionTokens) -> ApiResponse {
+    let mut body = json!({
+        "sessionId": tokens.token,
+    });
+    if let Some(refresh_token) = &tokens.refresh_token {
+        body["refreshToken"] = json!(refresh_token);
     }
 
-    let session_id = match store.create(req.username).await {
-        Ok(session_id) => session_id,
-        Err(err) => {
-            return Ok(response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({ "error": err.to_string() }).to_string(),
-            ))
-        }
-    };
+    let res = response(StatusCode::OK, body.to_string());
+    session_cookie(store, res, Some((&tokens.token, tokens.expires_at)))
+}
 
-    Ok(response(
+/// the `username` of `/users/:username/...` routes.
+#[derive(Debug, Deserialize)]
+pub struct UserPath {
+    username: String,
+}
+
+/// revokes every session of the bearer's user.
+///
+/// this used to be `DELETE /sessions/:username`, a path that now revokes a
+/// single session by id, see `delete_session`. Clients of the old path get a
+/// 404, or revoke nothing, until they move here.
+#[instrument(skip_all)]
+pub async fn delete_user_sessions<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, store, .. }: AuthenticatedSession<B>,
+    Path(path): Path<UserPath>,
+) -> ApiResponse {
+    if session.username != path.username {
+        return Err(AppError::Unauthorized("Invalid session".to_owned()).into());
+    }
+
+    let deleted = store.delete_user_sessions(session.username.clone()).await?;
+
+    let res = response(
         StatusCode::OK,
         json!({
-            "sessionId": session_id,
+            "username": session.username,
+            "deleted": deleted,
         })
         .to_string(),
-    ))
+    );
+    session_cookie(&store, res, None)
 }
 
+/// the optional `id` of `/sessions/:id`.
 #[derive(Debug, Deserialize)]
-struct CreateSessionRequest {
-    username: String,
-    password: String,
+pub struct SessionPath {
+    id: Option<String>,
 }
 
-#[instrument(skip(store))]
-pub async fn delete_user_sessions(
-    store: &SessionStore<'_>,
-    event: Request,
-) -> Result<Response<String>, E> {
-    let session_id = match event
-        .headers()
-        .get(http::header::AUTHORIZATION)
-        .map(|h| {
-            let session_id = h
-                .to_str()
-                .unwrap_or("")
-                .to_lowercase()
-                .replace("bearer ", "");
-
-            if session_id.is_empty() {
-                return None;
-            }
-            Some(session_id)
+/// revokes the session in the path, by the id `list_user_sessions` returns, or
+/// the bearer session itself when the path names none. Only sessions of the
+/// bearer's user can be revoked.
+#[instrument(skip_all)]
+pub async fn delete_session<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, store, .. }: AuthenticatedSession<B>,
+    Path(path): Path<SessionPath>,
+) -> ApiResponse {
+    let session_id = path.id.unwrap_or_else(|| session.id.clone());
+    let logout = session_id == session.id;
+
+    if !store.delete(session_id, session.username.clone()).await? {
+        // someone else's session is reported as missing rather than forbidden.
+        return Err(AppError::NotFound("Session does not exist.".to_owned()).into());
+    }
+
+    let res = response(
+        StatusCode::OK,
+        json!({
+            "username": session.username,
+            "deleted": 1,
         })
-        .flatten()
-    {
-        Some(session_id) => session_id,
-        None => {
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({ "error": "Missing Header: ".to_owned() }).to_string(),
-            ))
+        .to_string(),
+    );
+    if !logout {
+        return Ok(res);
+    }
+    session_cookie(&store, res, None)
+}
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListSessionsQuery {
+    limit: Option<usize>,
+    next_token: Option<String>,
+}
+
+/// lists the active sessions of the user in the path, who must own the bearer
+/// session. Paginated through the `limit` and `nextToken` query parameters.
+#[instrument(skip_all)]
+pub async fn list_user_sessions<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, store, .. }: AuthenticatedSession<B>,
+    Path(path): Path<UserPath>,
+    Query(query): Query<ListSessionsQuery>,
+) -> ApiResponse {
+    if session.username != path.username {
+        return Err(AppError::Unauthorized("Invalid session".to_owned()).into());
+    }
+
+    let limit = match query.limit {
+        None => DEFAULT_PAGE_SIZE,
+        Some(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => limit,
+        Some(_) => {
+            let invalid = AppError::Validation("invalid query parameters".to_owned());
+            return Err(Rejection::new(
+                invalid.status_code(),
+                ErrorBody::from(&invalid)
+                    .with_field_error("limit", &format!("must be between 1 and {}", MAX_PAGE_SIZE)),
+            ));
         }
     };
 
-    let session = match store.get(session_id.to_owned()).await {
-        Ok(session) => session,
+    let page = store
+        .list_user_sessions(path.username, limit, query.next_token)
+        .await?;
+
+    let sessions: Vec<_> = page
+        .sessions
+        .iter()
+        .map(|s| {
+            json!({
+                "id": s.id,
+                "current": s.id == session.id,
+                "createdAt": s.created_at.to_rfc3339(),
+                "expiresAt": s.expires_at.to_rfc3339(),
+                "userAgent": s.client.user_agent,
+                "ipAddress": s.client.ip_address,
+            })
+        })
+        .collect();
+
+    Ok(response(
+        StatusCode::OK,
+        json!({
+            "sessions": sessions,
+            "nextToken": page.next_token,
+        })
+        .to_string(),
+    ))
+}
+
+#[instrument(skip_all)]
+pub async fn get_session<B: SessionBackend + 'static>(
+    AuthenticatedSession {
+        mut session,
+        store,
+        token,
+    }: AuthenticatedSession<B>,
+) -> ApiResponse {
+    // sliding expiration is opt-in through the store configuration.
+    let touched = match store.touch(&mut session).await {
+        Ok(touched) => touched,
         Err(err) => {
-            return Ok(response(
-                StatusCode::UNAUTHORIZED,
-                json!({ "error": err.to_string() }).to_string(),
-            ))
+            warn!("failed to touch session: {}", err);
+            false
         }
     };
 
-    let username = match event.path_parameters().first("username") {
-        Some(username) => username.to_owned(),
-        None => return Ok(internal_server_error(AppError::new("missing username"))),
-    };
-    if session.username != username {
-        return Ok(response(
-            StatusCode::UNAUTHORIZED,
-            json!({ "error": "Invalid session" }).to_string(),
-        ));
+    let res = response(
+        StatusCode::OK,
+        json!({
+            "username": session.username,
+        })
+        .to_string(),
+    );
+    if !touched {
+        return Ok(res);
     }
+    // the cookie follows the session it keeps alive.
+    session_cookie(&store, res, Some((&token, session.expires_at)))
+}
 
-    if let Err(e) = store.delete_user_sessions(session.username.clone()).await {
-        return Ok(response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            json!({ "error": e.to_string() }).to_string(),
-        ));
+#[instrument(skip_all)]
+pub async fn get_session_data<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, .. }: AuthenticatedSession<B>,
+) -> ApiResponse {
+    Ok(response(
+        StatusCode::OK,
+        json!({
+            "data": session.data,
+        })
+        .to_string(),
+    ))
+}
+
+/// replaces the data of the bearer session with the JSON object in the body.
+#[instrument(skip_all)]
+pub async fn put_session_data<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, store, .. }: AuthenticatedSession<B>,
+    Json(data): Json<SessionData>,
+) -> ApiResponse {
+    if !store.set_data(session.id, data.clone()).await? {
+        return Err(lookup_failure(SessionLookup::NotFound).into());
     }
 
     Ok(response(
         StatusCode::OK,
         json!({
-            "username": session.username,
+            "data": data,
         })
         .to_string(),
     ))
 }
 
-#[instrument(skip(store))]
-pub async fn get_session(store: &SessionStore<'_>, event: Request) -> Result<Response<String>, E> {
-    let session_id = match event
-        .headers()
-        .get(http::header::AUTHORIZATION)
-        .map(|h| {
-            let session_id = h
-                .to_str()
-                .unwrap_or("")
-                .to_lowercase()
-                .replace("bearer ", "");
-
-            if session_id.is_empty() {
-                return None;
-            }
-            Some(session_id)
-        })
-        .flatten()
-    {
-        Some(session_id) => session_id,
-        None => {
-            return Ok(response(
-                StatusCode::BAD_REQUEST,
-                json!({ "error": "Missing Header: ".to_owned() }).to_string(),
-            ))
-        }
+/// merges the JSON object in the body into the data of the bearer session,
+/// `null` values removing their key.
+#[instrument(skip_all)]
+pub async fn patch_session_data<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, store, .. }: AuthenticatedSession<B>,
+    Json(changes): Json<SessionData>,
+) -> ApiResponse {
+    let data = match store.update_data(session.id, changes).await? {
+        Some(data) => data,
+        None => return Err(lookup_failure(SessionLookup::NotFound).into()),
     };
 
-    info!("sessionId: {}", session_id);
+    Ok(response(
+        StatusCode::OK,
+        json!({
+            "data": data,
+        })
+        .to_string(),
+    ))
+}
 
-    let session = match store.get(session_id.to_owned()).await {
-        Ok(session) => session,
-        Err(err) => {
-            return Ok(response(
-                StatusCode::UNAUTHORIZED,
-                json!({ "error": err.to_string() }).to_string(),
-            ))
-        }
-    };
+/// exchanges the bearer session for a short-lived JWT, see `jwt`.
+#[instrument(skip_all)]
+pub async fn create_session_token<B: SessionBackend + 'static>(
+    AuthenticatedSession { session, store, .. }: AuthenticatedSession<B>,
+) -> ApiResponse {
+    let config = store.config().jwt().ok_or_else(jwt_disabled)?;
+    let claims = JwtClaims::new(&session, config, Utc::now());
+    let token = config.keys().sign(&claims)?;
 
     Ok(response(
         StatusCode::OK,
         json!({
-            "username": session.username,
+            "token": token,
+            "tokenType": "Bearer",
+            "expiresIn": claims.exp - claims.iat,
         })
         .to_string(),
     ))
 }
 
-#[instrument(skip(_store))]
-pub async fn health_check(_store: &SessionStore<'_>, event: Request) -> Result<Response<String>, E> {
+/// publishes the public keys JWTs are signed with, current and previous.
+#[instrument(skip_all)]
+pub async fn jwks<B: SessionBackend + 'static>(
+    Extension(store): Extension<Arc<SessionStore<B>>>,
+) -> ApiResponse {
+    let config = store.config().jwt().ok_or_else(jwt_disabled)?;
+
+    let mut res = response(StatusCode::OK, config.keys().jwks().to_string());
+    // verifiers refetch the set when they meet an unknown key id.
+    res.headers_mut().insert(
+        http::header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=300"),
+    );
+    Ok(res)
+}
+
+fn jwt_disabled() -> AppError {
+    AppError::NotFound("JWTs are not enabled.".to_owned())
+}
+
+/// the session of the bearer token, or else of the session cookie when one is
+/// configured, along with the store holding it.
+pub struct AuthenticatedSession<B> {
+    pub session: Session,
+    pub store: Arc<SessionStore<B>>,
+    /// the token the session was presented with.
+    pub token: String,
+}
+
+#[async_trait]
+impl<B: SessionBackend + 'static> FromRequest for AuthenticatedSession<B> {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        let Extension(store) = Extension::<Arc<SessionStore<B>>>::from_request(request).await?;
+        let session_id = match BearerToken::from_headers(request.headers())? {
+            Some(BearerToken(token)) => token,
+            None => match store.config().cookie() {
+                Some(config) => {
+                    let session_id = cookie(request, config.name()).ok_or_else(|| {
+                        AppError::Unauthorized("missing session cookie or bearer token".to_owned())
+                    })?;
+                    // browsers attach SameSite=None cookies to requests any
+                    // other site makes, so those may only read.
+                    if config.same_site() == SameSite::None
+                        && !request.method().is_safe()
+                        && is_cross_site(request)
+                    {
+                        return Err(AppError::Unauthorized(
+                            "cross-site requests must use a bearer token".to_owned(),
+                        )
+                        .into());
+                    }
+                    session_id
+                }
+                None => BearerToken::from_request(request).await?.0,
+            },
+        };
+
+        match store.get(session_id.clone()).await? {
+            SessionLookup::Found(session) => Ok(AuthenticatedSession {
+                session: *session,
+                store,
+                token: session_id,
+            }),
+            lookup => Err(Rejection::invalid_token(lookup_failure(lookup))),
+        }
+    }
+}
+
+/// whether a browser sent `request` on behalf of another site, going by
+/// `Sec-Fetch-Site` or, for browsers without it, by `Origin`. Requests from
+/// other clients carry neither.
+fn is_cross_site(request: &Request) -> bool {
+    let headers = request.headers();
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(site) = header("sec-fetch-site") {
+        return site == "cross-site";
+    }
+    match header(http::header::ORIGIN.as_str()) {
+        Some(origin) => {
+            let origin_host = origin.split_once("://").map(|(_, host)| host);
+            origin_host.is_none() || origin_host != header(http::header::HOST.as_str())
+        }
+        None => false,
+    }
+}
+
+/// describes the client behind a request, as seen through the load balancer.
+#[async_trait]
+impl FromRequest for ClientMetadata {
+    async fn from_request(request: &Request) -> Result<Self, Rejection> {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned())
+        };
+
+        Ok(ClientMetadata {
+            user_agent: header(http::header::USER_AGENT.as_str()),
+            // the load balancer appends the address it was reached from, the
+            // entries before it are whatever the client sent.
+            ip_address: header("x-forwarded-for")
+                .and_then(|ips| ips.rsplit(',').next().map(|ip| ip.trim().to_owned()))
+                .filter(|ip| !ip.is_empty()),
+        })
+    }
+}
+
+/// sets the session cookie on `res` to a token until its session expires, or
+/// clears it when there is no session left. A no-op unless sessions also
+/// travel in a cookie.
+fn session_cookie<B: SessionBackend>(
+    store: &SessionStore<B>,
+    mut res: Response<String>,
+    session: Option<(&str, DateTime<Utc>)>,
+) -> ApiResponse {
+    let cookie = match store.config().cookie() {
+        Some(cookie) => cookie,
+        None => return Ok(res),
+    };
+    let value = match session {
+        Some((token, expires_at)) => cookie.set(token, expires_at - Utc::now()),
+        None => cookie.clear(),
+    };
+
+    let value = HeaderValue::from_str(&value).map_err(AppError::backend)?;
+    res.headers_mut().append(http::header::SET_COOKIE, value);
+    Ok(res)
+}
+
+/// maps an unsuccessful session lookup to a 401 carrying a stable error code.
+fn lookup_failure(lookup: SessionLookup) -> AppError {
+    match lookup {
+        SessionLookup::Expired => AppError::Expired,
+        _ => AppError::InvalidSession,
+    }
+}
+
+#[instrument]
+pub async fn health_check() -> ApiResponse {
     Ok(Response::builder()
         .status(StatusCode::OK)
         .body("".to_owned())
         .unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use lambda_http::{Body, RequestExt};
+    use serde_json::Value;
+
+    use lazy_static::lazy_static;
+
+    use super::*;
+    use crate::{
+        alb::HandlerResponse,
+        backend::InMemoryBackend,
+        config::{JwtConfig, SessionConfig, SessionCookie, SignedTokens, SlidingExpiration},
+        credentials::StaticUsers,
+        hashing::HashKeys,
+        jwt::JwtKeys,
+        store::RefreshRecord,
+    };
+
+    lazy_static! {
+        static ref USERS: StaticUsers = {
+            // the lowest bcrypt cost keeps the tests fast.
+            let hash = bcrypt::hash("pingpong", 4).unwrap();
+            StaticUsers::new(HashMap::from([
+                ("alice".to_owned(), hash.clone()),
+                ("bob".to_owned(), hash),
+            ]))
+            .with_roles("alice", vec!["admin".to_owned()])
+        };
+    }
+
+    /// the id the session of `token` is stored under.
+    fn id_of<B: SessionBackend>(store: &SessionStore<B>, token: &str) -> String {
+        store.config().hash_keys().hash(token)
+    }
+
+    fn store() -> Arc<SessionStore<InMemoryBackend>> {
+        Arc::new(SessionStore::with_backend(InMemoryBackend::new()))
+    }
+
+    /// routes `request` through the whole service.
+    async fn send<B: SessionBackend + 'static>(
+        store: &Arc<SessionStore<B>>,
+        request: Request,
+    ) -> HandlerResponse {
+        let verifier: Arc<dyn CredentialVerifier> = Arc::new(USERS.clone());
+        router(store.clone(), verifier)
+            .unwrap()
+            .handle(request)
+            .await
+    }
+
+    fn body(res: &Response<String>) -> Value {
+        serde_json::from_str(res.body()).expect("response body should be JSON")
+    }
+
+    fn create_request(username: &str, password: &str) -> Request {
+        http::Request::builder()
+            .method("POST")
+            .uri("/sessions")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "username": username, "password": password }).to_string(),
+            ))
+            .unwrap()
+    }
+
+    fn refresh_request(refresh_token: &str) -> Request {
+        http::Request::builder()
+            .method("POST")
+            .uri("/sessions/refresh")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "refreshToken": refresh_token }).to_string(),
+            ))
+            .unwrap()
+    }
+
+    fn bearer_request(method: &str, uri: &str, session_id: &str) -> Request {
+        http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                http::header::AUTHORIZATION,
+                format!("Bearer {}", session_id),
+            )
+            .body(Body::Empty)
+            .unwrap()
+    }
+
+    fn get_request(uri: &str) -> Request {
+        http::Request::builder().uri(uri).body(Body::Empty).unwrap()
+    }
+
+    fn data_request(method: &str, session_id: &str, payload: Value) -> Request {
+        http::Request::builder()
+            .method(method)
+            .uri("/sessions/data")
+            .header(
+                http::header::AUTHORIZATION,
+                format!("Bearer {}", session_id),
+            )
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    }
+
+    fn list_request(username: &str, session_id: &str, query: &[(&str, &str)]) -> Request {
+        bearer_request("GET", &format!("/users/{}/sessions", username), session_id)
+            .with_query_string_parameters(
+                query
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+                    .collect::<HashMap<String, Vec<String>>>(),
+            )
+    }
+
+    async fn login(store: &Arc<SessionStore<InMemoryBackend>>, username: &str) -> String {
+        let res = send(store, create_request(username, "pingpong"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        body(&res)["sessionId"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn create_session_rejects_wrong_password() {
+        let store = store();
+        let res = send(&store, create_request("alice", "nope")).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(store.backend().is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_session_rejects_unknown_user() {
+        let store = store();
+        let res = send(&store, create_request("mallory", "pingpong"))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(store.backend().is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_session_requires_json() {
+        let store = store();
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/sessions")
+            .body(Body::from("username=alice"))
+            .unwrap();
+
+        let res = send(&store, request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn get_session_returns_username() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+
+        let res = send(&store, bearer_request("GET", "/sessions", &session_id))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["username"], "alice");
+    }
+
+    #[tokio::test]
+    async fn signed_tokens_stop_working_once_revoked() {
+        let signed = SignedTokens::new(HashKeys::new("s1", "signing"));
+        let store = Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new())
+                .with_config(SessionConfig::new().with_signed_tokens(signed)),
+        );
+        let token = login(&store, "alice").await;
+        assert!(token.starts_with("ddbst_"));
+
+        let res = send(&store, bearer_request("GET", "/sessions", &token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["username"], "alice");
+
+        let request = bearer_request("DELETE", "/users/alice/sessions", &token);
+        assert_eq!(
+            send(&store, request).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        let res = send(&store, bearer_request("GET", "/sessions", &token))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn get_session_touches_sliding_sessions() {
+        let store = Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new()).with_config(
+                SessionConfig::new().with_sliding_expiration(SlidingExpiration::new(
+                    chrono::Duration::minutes(30),
+                    chrono::Duration::minutes(1),
+                )),
+            ),
+        );
+        let created_at = chrono::Utc::now() - chrono::Duration::minutes(10);
+        let session_id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+
+        let res = send(&store, bearer_request("GET", "/sessions", &session_id))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let session = store
+            .backend()
+            .get(&id_of(&store, &session_id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(session.expires_at > created_at + chrono::Duration::minutes(30));
+    }
+
+    #[tokio::test]
+    async fn get_session_rejects_unknown_session() {
+        let store = store();
+        let res = send(&store, bearer_request("GET", "/sessions", "unknown"))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(body(&res)["code"], "invalid_session");
+    }
+
+    /// a backend whose every call fails, like DynamoDB during an outage.
+    struct UnavailableBackend;
+
+    #[async_trait::async_trait]
+    impl SessionBackend for UnavailableBackend {
+        async fn get(&self, _: &str) -> Result<Option<Session>, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn create(&self, _: &Session) -> Result<(), AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn create_with_refresh(
+            &self,
+            _: &Session,
+            _: &RefreshRecord,
+        ) -> Result<(), AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn get_refresh(&self, _: &str) -> Result<Option<RefreshRecord>, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn rotate(
+            &self,
+            _: &RefreshRecord,
+            _: &Session,
+            _: &RefreshRecord,
+        ) -> Result<(), AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn touch(&self, _: &str, _: chrono::DateTime<chrono::Utc>) -> Result<bool, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn set_data(&self, _: &str, _: &SessionData) -> Result<bool, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn update_data(
+            &self,
+            _: &str,
+            _: &SessionData,
+        ) -> Result<Option<SessionData>, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn delete(&self, _: &str) -> Result<(), AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn delete_if_owned(&self, _: &str, _: &str) -> Result<bool, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn delete_user_sessions(&self, _: &str) -> Result<usize, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn delete_family(&self, _: &str, _: &str) -> Result<usize, AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+        async fn list_user_sessions(
+            &self,
+            _: &str,
+            _: usize,
+            _: Option<&str>,
+        ) -> Result<(Vec<Session>, Option<String>), AppError> {
+            Err(AppError::backend("service unavailable"))
+        }
+    }
+
+    #[tokio::test]
+    async fn get_session_accepts_the_session_cookie() {
+        let store = Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new())
+                .with_config(SessionConfig::new().with_cookie(SessionCookie::new("sid"))),
+        );
+        let session_id = login(&store, "alice").await;
+
+        let request = http::Request::builder()
+            .uri("/sessions")
+            .header(
+                http::header::COOKIE,
+                format!("theme=dark; sid={}", session_id),
+            )
+            .body(Body::Empty)
+            .unwrap();
+        let res = send(&store, request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["username"], "alice");
+
+        // a bearer token wins over the cookie.
+        let mut request = bearer_request("GET", "/sessions", "unknown");
+        request.headers_mut().insert(
+            http::header::COOKIE,
+            format!("sid={}", session_id).parse().unwrap(),
+        );
+        let res = send(&store, request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers()[http::header::WWW_AUTHENTICATE],
+            r#"Bearer error="invalid_token""#
+        );
+    }
+
+    #[tokio::test]
+    async fn session_cookie_is_set_and_cleared() {
+        let store = Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new()).with_config(
+                SessionConfig::new()
+                    .with_ttl(chrono::Duration::hours(1))
+                    .with_cookie(SessionCookie::new("sid")),
+            ),
+        );
+
+        let res = send(&store, create_request("alice", "pingpong"))
+            .await
+            .unwrap();
+        let session_id = body(&res)["sessionId"].as_str().unwrap().to_owned();
+        assert_eq!(
+            res.headers()[http::header::SET_COOKIE],
+            format!(
+                "sid={}; Max-Age=3600; Path=/; SameSite=Lax; Secure; HttpOnly",
+                session_id
+            )
+        );
+        let other_id = id_of(&store, &login(&store, "alice").await);
+
+        let cookie_request = |method: &str, uri: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(http::header::COOKIE, format!("sid={}", session_id))
+                .body(Body::Empty)
+                .unwrap()
+        };
+
+        // revoking another session keeps ours.
+        let res = send(
+            &store,
+            cookie_request("DELETE", &format!("/sessions/{}", other_id)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(http::header::SET_COOKIE).is_none());
+
+        let res = send(&store, cookie_request("DELETE", "/sessions"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[http::header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .starts_with("sid=; Max-Age=0;"));
+        assert!(store.backend().is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_site_none_cookies_are_refused_on_cross_site_writes() {
+        let store = Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new()).with_config(
+                SessionConfig::new()
+                    .with_cookie(SessionCookie::new("sid").with_same_site(SameSite::None)),
+            ),
+        );
+        let cookie_request = |method: &str, session_id: &str, headers: &[(&str, &str)]| {
+            let mut request = http::Request::builder()
+                .method(method)
+                .uri("/sessions")
+                .header(http::header::HOST, "auth.example.com")
+                .header(http::header::COOKIE, format!("sid={}", session_id));
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            request.body(Body::Empty).unwrap()
+        };
+
+        let session_id = login(&store, "alice").await;
+        for headers in [
+            &[("sec-fetch-site", "cross-site")][..],
+            &[("origin", "https://evil.example")],
+            &[("origin", "null")],
+        ] {
+            let request = cookie_request("DELETE", &session_id, headers);
+            let res = send(&store, request).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(store.backend().len(), 1);
+        }
+
+        // reads stay open to other sites.
+        let request = cookie_request("GET", &session_id, &[("sec-fetch-site", "cross-site")]);
+        let res = send(&store, request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for headers in [
+            &[("sec-fetch-site", "same-origin")][..],
+            &[("origin", "https://auth.example.com")],
+            &[],
+        ] {
+            let session_id = login(&store, "bob").await;
+            let request = cookie_request("DELETE", &session_id, headers);
+            let res = send(&store, request).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        assert_eq!(store.backend().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_session_reports_backend_failures() {
+        let store = Arc::new(SessionStore::with_backend(UnavailableBackend));
+        let token = crate::token::generate("0").unwrap();
+        let res = send(&store, bearer_request("GET", "/sessions", &token))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body(&res)["code"], "backend_error");
+    }
+
+    #[tokio::test]
+    async fn get_session_rejects_expired_session() {
+        let store = store();
+        let token = crate::token::generate("0").unwrap();
+        let created_at = chrono::Utc::now() - chrono::Duration::days(8);
+        store
+            .backend()
+            .create(&crate::store::Session::new(
+                id_of(&store, &token),
+                "alice".to_owned(),
+                created_at,
+                created_at + chrono::Duration::days(7),
+            ))
+            .await
+            .unwrap();
+
+        let res = send(&store, bearer_request("GET", "/sessions", &token))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(body(&res)["code"], "session_expired");
+    }
+
+    fn jwt_store(keys: &JwtKeys) -> Arc<SessionStore<InMemoryBackend>> {
+        Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new()).with_config(
+                SessionConfig::new().with_jwt(JwtConfig::new(keys.clone()).with_issuer("sessions")),
+            ),
+        )
+    }
+
+    fn jwt_keys() -> JwtKeys {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        JwtKeys::new("k1", pkcs8.as_ref()).unwrap()
+    }
+
+    /// the claims of the JWT the session of `session_id` is exchanged for.
+    async fn jwt_claims(store: &Arc<SessionStore<InMemoryBackend>>, session_id: &str) -> Value {
+        let res = send(store, bearer_request("POST", "/sessions/token", session_id))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["expiresIn"], 300);
+
+        let token = body(&res)["token"].as_str().unwrap().to_owned();
+        let claims = token.split('.').nth(1).unwrap();
+        serde_json::from_slice(&base64::decode_config(claims, base64::URL_SAFE_NO_PAD).unwrap())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_are_exchanged_for_jwts() {
+        let keys = jwt_keys();
+        let store = jwt_store(&keys);
+        let session_id = login(&store, "alice").await;
+
+        let claims = jwt_claims(&store, &session_id).await;
+        assert_eq!(claims["iss"], "sessions");
+        assert_eq!(claims["sub"], "alice");
+        assert_eq!(claims["sid"], id_of(&store, &session_id));
+        assert_eq!(claims["roles"], json!(["admin"]));
+
+        let res = send(&store, get_request("/.well-known/jwks.json"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res), keys.jwks());
+    }
+
+    #[tokio::test]
+    async fn client_written_roles_never_reach_jwts() {
+        let store = jwt_store(&jwt_keys());
+        let session_id = login(&store, "bob").await;
+
+        let roles = json!({ "roles": ["admin"] });
+        let res = send(&store, data_request("PUT", &session_id, roles))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(jwt_claims(&store, &session_id).await["roles"], json!([]));
+
+        let roles = json!({ "roles": ["admin", "billing"] });
+        send(&store, data_request("PATCH", &session_id, roles))
+            .await
+            .unwrap();
+        assert_eq!(jwt_claims(&store, &session_id).await["roles"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn jwt_routes_are_missing_unless_configured() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+
+        let res = send(
+            &store,
+            bearer_request("POST", "/sessions/token", &session_id),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = send(&store, get_request("/.well-known/jwks.json"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_rotate_once() {
+        let store = Arc::new(
+            SessionStore::with_backend(InMemoryBackend::new())
+                .with_config(SessionConfig::new().with_refresh_ttl(chrono::Duration::days(30))),
+        );
+        let res = send(&store, create_request("alice", "pingpong"))
+            .await
+            .unwrap();
+        let refresh_token = body(&res)["refreshToken"].as_str().unwrap().to_owned();
+
+        let res = send(&store, refresh_request(&refresh_token)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let session_id = body(&res)["sessionId"].as_str().unwrap().to_owned();
+        assert_ne!(body(&res)["refreshToken"], refresh_token);
+
+        let res = send(&store, bearer_request("GET", "/sessions", &session_id))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // replaying the first refresh token revokes the rotated session too.
+        let res = send(&store, refresh_request(&refresh_token)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(store.backend().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_only_issued_when_enabled() {
+        let store = store();
+        let res = send(&store, create_request("alice", "pingpong"))
+            .await
+            .unwrap();
+        assert!(body(&res).get("refreshToken").is_none());
+
+        let refresh_token = crate::token::generate_refresh("0").unwrap();
+        let res = send(&store, refresh_request(&refresh_token)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn session_data_endpoints() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+
+        let res = send(&store, bearer_request("GET", "/sessions/data", &session_id))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["data"], json!({}));
+
+        let payload = json!({ "roles": ["admin"], "tenant": "acme" });
+        let res = send(&store, data_request("PUT", &session_id, payload.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["data"], payload);
+
+        let patch = json!({ "tenant": null, "csrf": "token" });
+        let res = send(&store, data_request("PATCH", &session_id, patch))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            body(&res)["data"],
+            json!({ "roles": ["admin"], "csrf": "token" })
+        );
+
+        let res = send(&store, bearer_request("GET", "/sessions/data", &session_id))
+            .await
+            .unwrap();
+        assert_eq!(
+            body(&res)["data"],
+            json!({ "roles": ["admin"], "csrf": "token" })
+        );
+    }
+
+    #[tokio::test]
+    async fn put_session_data_requires_an_object() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+
+        let res = send(&store, data_request("PUT", &session_id, json!([1, 2])))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn list_user_sessions_pages_through_sessions() {
+        let store = store();
+        let request = create_request("alice", "pingpong");
+        let (mut parts, payload) = request.into_parts();
+        parts
+            .headers
+            .insert(http::header::USER_AGENT, "curl/7.79.1".parse().unwrap());
+        // a made up hop, then the one the load balancer appended.
+        parts.headers.insert(
+            "x-forwarded-for",
+            "10.0.0.1, 72.12.164.125".parse().unwrap(),
+        );
+        let res = send(&store, Request::from_parts(parts, payload))
+            .await
+            .unwrap();
+        let session_id = body(&res)["sessionId"].as_str().unwrap().to_owned();
+        login(&store, "alice").await;
+        login(&store, "alice").await;
+        login(&store, "bob").await;
+
+        let res = send(
+            &store,
+            list_request("alice", &session_id, &[("limit", "2")]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let first = body(&res);
+        assert_eq!(first["sessions"].as_array().unwrap().len(), 2);
+        let next_token = first["nextToken"].as_str().unwrap();
+
+        let res = send(
+            &store,
+            list_request(
+                "alice",
+                &session_id,
+                &[("limit", "2"), ("nextToken", next_token)],
+            ),
+        )
+        .await
+        .unwrap();
+        let second = body(&res);
+        assert_eq!(second["sessions"].as_array().unwrap().len(), 1);
+        assert!(second["nextToken"].is_null());
+
+        let sessions: Vec<Value> = first["sessions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(second["sessions"].as_array().unwrap())
+            .cloned()
+            .collect();
+        let current: Vec<&Value> = sessions.iter().filter(|s| s["current"] == true).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["userAgent"], "curl/7.79.1");
+        assert_eq!(current[0]["ipAddress"], "72.12.164.125");
+        assert!(sessions
+            .iter()
+            .all(|s| !s["id"].as_str().unwrap().contains(&session_id)));
+    }
+
+    #[tokio::test]
+    async fn list_user_sessions_rejects_other_users() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+
+        let res = send(&store, list_request("bob", &session_id, &[]))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = send(
+            &store,
+            list_request("alice", &session_id, &[("limit", "0")]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(&res)["fieldErrors"][0]["field"], "limit");
+    }
+
+    fn delete_request(session_id: &str, target: Option<&str>) -> Request {
+        match target {
+            Some(target) => bearer_request("DELETE", &format!("/sessions/{}", target), session_id),
+            None => bearer_request("DELETE", "/sessions", session_id),
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_session_logs_out_the_bearer() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+        login(&store, "alice").await;
+
+        let res = send(&store, delete_request(&session_id, None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(store.backend().len(), 1);
+
+        let res = send(&store, bearer_request("GET", "/sessions", &session_id))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn delete_session_revokes_another_session_of_the_user() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+        let other_id = id_of(&store, &login(&store, "alice").await);
+        let bob_id = id_of(&store, &login(&store, "bob").await);
+
+        let res = send(&store, delete_request(&session_id, Some(&bob_id)))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(store.backend().len(), 3);
+
+        let res = send(&store, delete_request(&session_id, Some(&other_id)))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(store.backend().get(&other_id).await.unwrap().is_none());
+        assert!(store
+            .backend()
+            .get(&id_of(&store, &session_id))
+            .await
+            .unwrap()
+            .is_some());
+
+        let res = send(&store, delete_request(&session_id, Some(&other_id)))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn delete_user_sessions_removes_all_sessions() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+        login(&store, "alice").await;
+        login(&store, "bob").await;
+
+        let request = bearer_request("DELETE", "/users/alice/sessions", &session_id);
+        let res = send(&store, request).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["deleted"], 2);
+        assert_eq!(store.backend().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delete_user_sessions_rejects_other_users() {
+        let store = store();
+        let session_id = login(&store, "alice").await;
+        login(&store, "bob").await;
+
+        let request = bearer_request("DELETE", "/users/bob/sessions", &session_id);
+        let res = send(&store, request).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(store.backend().len(), 2);
+    }
+}
@@ -0,0 +1,106 @@
+//! # Storage backends for `SessionStore`.
+//!
+//! `SessionStore` owns the session rules (identifiers, lifetimes) and delegates
+//! persistence to a `SessionBackend`. The DynamoDB implementation is the one
+//! deployed by the stack; other implementations only need to honour the same
+//! contract.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    errors::AppError,
+    store::{RefreshRecord, Session, SessionData},
+};
+
+pub mod dynamodb;
+pub mod memory;
+
+pub use dynamodb::DynamoDbBackend;
+pub use memory::InMemoryBackend;
+
+/// Persistence operations required by `SessionStore`.
+///
+/// Sessions are keyed by their `id` and indexed by `username`, mirroring the
+/// `PK` / `GSI1PK` layout of the DynamoDB table. Refresh tokens share the
+/// table and the index, but are never returned as sessions.
+#[async_trait]
+pub trait SessionBackend: Send + Sync {
+    /// Return the session stored under `id`, if any.
+    async fn get(&self, id: &str) -> Result<Option<Session>, AppError>;
+
+    /// Persist a new session.
+    async fn create(&self, session: &Session) -> Result<(), AppError>;
+
+    /// Persist a new session along with its refresh token, atomically.
+    async fn create_with_refresh(
+        &self,
+        session: &Session,
+        refresh: &RefreshRecord,
+    ) -> Result<(), AppError>;
+
+    /// Return the refresh token stored under `id`, if any.
+    async fn get_refresh(&self, id: &str) -> Result<Option<RefreshRecord>, AppError>;
+
+    /// Trade the refresh token `old` for `session` and its token `refresh`.
+    ///
+    /// In one transaction, `old` is marked as rotated, the session it renewed
+    /// is removed and the new items are created. Fails with `Conflict` when
+    /// `old` is missing or was already rotated.
+    async fn rotate(
+        &self,
+        old: &RefreshRecord,
+        session: &Session,
+        refresh: &RefreshRecord,
+    ) -> Result<(), AppError>;
+
+    /// Move the expiry of the session stored under `id` to `expires_at`.
+    ///
+    /// The write is conditional: it only happens if the session still exists
+    /// and `expires_at` is later than its current expiry. Returns whether the
+    /// session was updated.
+    async fn touch(&self, id: &str, expires_at: DateTime<Utc>) -> Result<bool, AppError>;
+
+    /// Replace the data of the session stored under `id`. Returns whether the
+    /// session exists.
+    async fn set_data(&self, id: &str, data: &SessionData) -> Result<bool, AppError>;
+
+    /// Merge `changes` into the data of the session stored under `id`, a `null`
+    /// value removing its key. Returns the resulting data, `None` if the session
+    /// does not exist.
+    async fn update_data(
+        &self,
+        id: &str,
+        changes: &SessionData,
+    ) -> Result<Option<SessionData>, AppError>;
+
+    /// Remove the session stored under `id`. Removing a missing session is
+    /// not an error.
+    async fn delete(&self, id: &str) -> Result<(), AppError>;
+
+    /// Remove the session stored under `id` if it belongs to `username`.
+    ///
+    /// The check and the delete happen atomically. Returns whether a session
+    /// was removed.
+    async fn delete_if_owned(&self, id: &str, username: &str) -> Result<bool, AppError>;
+
+    /// Remove every session belonging to `username`, returning how many were
+    /// deleted. Their refresh tokens go with them.
+    async fn delete_user_sessions(&self, username: &str) -> Result<usize, AppError>;
+
+    /// Remove the sessions and refresh tokens of `username` in `family`,
+    /// returning how many sessions were deleted.
+    async fn delete_family(&self, username: &str, family: &str) -> Result<usize, AppError>;
+
+    /// Return up to `limit` sessions belonging to `username`, resuming after the
+    /// session id `start_after`. Refresh tokens do not count towards `limit`.
+    ///
+    /// Alongside the sessions comes the id to resume after for the next page,
+    /// `None` once every session was returned.
+    async fn list_user_sessions(
+        &self,
+        username: &str,
+        limit: usize,
+        start_after: Option<&str>,
+    ) -> Result<(Vec<Session>, Option<String>), AppError>;
+}
@@ -0,0 +1,935 @@
+//! # DynamoDB implementation of `SessionBackend`.
+//!
+//! Sessions are stored under `PK = id`, with `GSI1PK = username` feeding the
+//! `GSI1` index and `TTL` holding the expiry as epoch seconds.
+//!
+//! Refresh tokens are items of the same shape, told apart by `kind = refresh`.
+//! Being in `GSI1`, they are deleted along with the sessions of their user.
+
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{
+    error::{TransactWriteItemsError, TransactWriteItemsErrorKind, UpdateItemError},
+    model::{
+        AttributeValue, Delete, DeleteRequest, Put, ReturnValue, TransactWriteItem, Update,
+        WriteRequest,
+    },
+    output::UpdateItemOutput,
+    types::SdkError,
+    Client,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{Number, Value};
+use std::{collections::HashMap, time};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    errors::AppError,
+    ext::AttributeValuesExt,
+    store::{ClientMetadata, RefreshRecord, Session, SessionData},
+};
+
+use super::SessionBackend;
+
+type Item = HashMap<String, AttributeValue>;
+
+const REFRESH_KIND: &str = "refresh";
+
+pub struct DynamoDbBackend {
+    table_name: String,
+    ddb: Client,
+}
+
+impl DynamoDbBackend {
+    pub fn new(ddb: Client, table_name: String) -> DynamoDbBackend {
+        DynamoDbBackend { table_name, ddb }
+    }
+
+    /// Fetch one page of the `GSI1` items belonging to `username`.
+    async fn query_user_sessions_page(
+        &self,
+        username: &str,
+        start_key: Option<Item>,
+        limit: Option<i32>,
+        sessions_only: bool,
+    ) -> Result<(Vec<Item>, Option<Item>), AppError> {
+        let mut query = self
+            .ddb
+            .query()
+            .set_limit(limit)
+            .table_name(self.table_name.clone())
+            .index_name("GSI1")
+            .key_condition_expression("#username = :username".to_owned())
+            .expression_attribute_names("#username".to_owned(), "GSI1PK".to_owned())
+            .expression_attribute_values(
+                ":username".to_owned(),
+                AttributeValue::S(username.to_owned()),
+            )
+            .set_exclusive_start_key(start_key);
+        if sessions_only {
+            // filtered after `limit` applies, pages may come back short.
+            query = query
+                .filter_expression("attribute_not_exists(#kind) OR #kind <> :refresh")
+                .expression_attribute_names("#kind".to_owned(), "kind".to_owned())
+                .expression_attribute_values(
+                    ":refresh".to_owned(),
+                    AttributeValue::S(REFRESH_KIND.to_owned()),
+                );
+        }
+        let res = query.send().await?;
+
+        info!("{} sessions found for {}", res.count(), username);
+
+        Ok((res.items.unwrap_or_default(), res.last_evaluated_key))
+    }
+
+    /// Delete the `GSI1` items of `username` matching `filter`, returning how
+    /// many of them were sessions.
+    async fn delete_user_items(
+        &self,
+        username: &str,
+        filter: impl Fn(&Item) -> bool,
+    ) -> Result<usize, AppError> {
+        let mut deleted = 0;
+        let mut start_key = None;
+        loop {
+            let (items, last_key) = self
+                .query_user_sessions_page(username, start_key, None, false)
+                .await?;
+            let items: Vec<Item> = items.into_iter().filter(|item| filter(item)).collect();
+            let sessions = items.iter().filter(|item| !is_refresh(item)).count();
+            let keys: Vec<AttributeValue> = items
+                .into_iter()
+                .filter_map(|mut item| item.remove("PK"))
+                .collect();
+
+            for chunk in keys.chunks(BATCH_WRITE_LIMIT) {
+                self.batch_delete(chunk).await?;
+            }
+            deleted += sessions;
+
+            start_key = match last_key {
+                Some(key) => Some(key),
+                None => break,
+            };
+        }
+
+        Ok(deleted)
+    }
+
+    /// Merge `changes` into the `data` map of the session stored under `id`.
+    async fn merge_data(
+        &self,
+        id: &str,
+        changes: &SessionData,
+    ) -> Result<UpdateItemOutput, SdkError<UpdateItemError>> {
+        let mut update = self
+            .ddb
+            .update_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .condition_expression("attribute_exists(PK)")
+            .expression_attribute_names("#data", "data")
+            .return_values(ReturnValue::AllNew);
+
+        // data keys are arbitrary, so every one of them goes through a placeholder.
+        let mut sets = Vec::new();
+        let mut removes = Vec::new();
+        for (idx, (key, value)) in changes.iter().enumerate() {
+            let name = format!("#k{}", idx);
+            update = update.expression_attribute_names(name.clone(), key.clone());
+            if value.is_null() {
+                removes.push(format!("#data.{}", name));
+            } else {
+                let placeholder = format!(":v{}", idx);
+                update = update
+                    .expression_attribute_values(placeholder.clone(), json_to_attribute(value));
+                sets.push(format!("#data.{} = {}", name, placeholder));
+            }
+        }
+
+        let mut expression = Vec::new();
+        if !sets.is_empty() {
+            expression.push(format!("SET {}", sets.join(", ")));
+        }
+        if !removes.is_empty() {
+            expression.push(format!("REMOVE {}", removes.join(", ")));
+        }
+
+        update.update_expression(expression.join(" ")).send().await
+    }
+
+    /// Give the session stored under `id` the `data` map it lacks, made of
+    /// `changes`.
+    async fn create_data(
+        &self,
+        id: &str,
+        changes: &SessionData,
+    ) -> Result<UpdateItemOutput, SdkError<UpdateItemError>> {
+        self.ddb
+            .update_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .update_expression("SET #data = :data")
+            .condition_expression("attribute_exists(PK) AND attribute_not_exists(#data)")
+            .expression_attribute_names("#data", "data")
+            .expression_attribute_values(":data", data_to_attribute(&without_nulls(changes)))
+            .return_values(ReturnValue::AllNew)
+            .send()
+            .await
+    }
+
+    /// Delete up to `BATCH_WRITE_LIMIT` items, retrying the ones DynamoDB
+    /// reports as unprocessed with an exponential backoff.
+    async fn batch_delete(&self, keys: &[AttributeValue]) -> Result<(), AppError> {
+        let mut requests: Vec<WriteRequest> = keys
+            .iter()
+            .map(|hk| {
+                WriteRequest::builder()
+                    .delete_request(
+                        DeleteRequest::builder()
+                            .key("PK".to_owned(), hk.to_owned())
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect();
+
+        let mut attempt = 0;
+        loop {
+            let res = self
+                .ddb
+                .batch_write_item()
+                .request_items(self.table_name.clone(), requests)
+                .send()
+                .await?;
+
+            requests = res
+                .unprocessed_items
+                .and_then(|mut unprocessed| unprocessed.remove(&self.table_name))
+                .unwrap_or_default();
+            if requests.is_empty() {
+                return Ok(());
+            }
+
+            attempt += 1;
+            if attempt >= MAX_BATCH_ATTEMPTS {
+                return Err(AppError::backend(format!(
+                    "{} deletes still unprocessed after {} attempts",
+                    requests.len(),
+                    attempt
+                )));
+            }
+            warn!("{} deletes unprocessed, retrying", requests.len());
+            tokio::time::sleep(backoff(attempt)).await;
+        }
+    }
+}
+
+/// DynamoDB rejects `BatchWriteItem` calls with more requests than this.
+const BATCH_WRITE_LIMIT: usize = 25;
+
+const MAX_BATCH_ATTEMPTS: u32 = 5;
+
+fn backoff(attempt: u32) -> time::Duration {
+    time::Duration::from_millis(50 * 2u64.pow(attempt))
+}
+
+#[async_trait]
+impl SessionBackend for DynamoDbBackend {
+    async fn get(&self, id: &str) -> Result<Option<Session>, AppError> {
+        let res = self
+            .ddb
+            .get_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .send()
+            .await?;
+
+        session_from_item(res.item)
+    }
+
+    async fn create(&self, session: &Session) -> Result<(), AppError> {
+        self.ddb
+            .put_item()
+            .table_name(self.table_name.to_owned())
+            .set_item(Some(session.into()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_with_refresh(
+        &self,
+        session: &Session,
+        refresh: &RefreshRecord,
+    ) -> Result<(), AppError> {
+        let put = |item: Item| {
+            TransactWriteItem::builder()
+                .put(
+                    Put::builder()
+                        .table_name(self.table_name.to_owned())
+                        .set_item(Some(item))
+                        .build(),
+                )
+                .build()
+        };
+        self.ddb
+            .transact_write_items()
+            .transact_items(put(session.into()))
+            .transact_items(put(refresh.into()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_refresh(&self, id: &str) -> Result<Option<RefreshRecord>, AppError> {
+        let res = self
+            .ddb
+            .get_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .send()
+            .await?;
+
+        match res.item {
+            Some(item) if is_refresh(&item) => Ok(Some(item.try_into()?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn rotate(
+        &self,
+        old: &RefreshRecord,
+        session: &Session,
+        refresh: &RefreshRecord,
+    ) -> Result<(), AppError> {
+        let spend = Update::builder()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(old.id.to_owned()))
+            .update_expression("SET #rotated = :true")
+            .condition_expression("attribute_exists(PK) AND #rotated = :false")
+            .expression_attribute_names("#rotated", "rotated")
+            .expression_attribute_values(":true", AttributeValue::Bool(true))
+            .expression_attribute_values(":false", AttributeValue::Bool(false))
+            .build();
+        // the renewed session may already be gone through TTL.
+        let revoke = Delete::builder()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(old.session_id.to_owned()))
+            .build();
+        let put = |item: Item| {
+            Put::builder()
+                .table_name(self.table_name.to_owned())
+                .set_item(Some(item))
+                .condition_expression("attribute_not_exists(PK)")
+                .build()
+        };
+
+        let res = self
+            .ddb
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().update(spend).build())
+            .transact_items(TransactWriteItem::builder().delete(revoke).build())
+            .transact_items(
+                TransactWriteItem::builder()
+                    .put(put(session.into()))
+                    .build(),
+            )
+            .transact_items(
+                TransactWriteItem::builder()
+                    .put(put(refresh.into()))
+                    .build(),
+            )
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError { err, .. }) if lost_race(&err) => Err(AppError::Conflict(
+                "refresh token already rotated".to_owned(),
+            )),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn touch(&self, id: &str, expires_at: DateTime<Utc>) -> Result<bool, AppError> {
+        let res = self
+            .ddb
+            .update_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .update_expression("SET #expires_at = :expires_at, #ttl = :ttl")
+            .condition_expression("attribute_exists(PK) AND #ttl < :ttl")
+            .expression_attribute_names("#expires_at", "expires_at")
+            .expression_attribute_names("#ttl", "TTL")
+            .expression_attribute_values(":expires_at", AttributeValue::S(expires_at.to_rfc3339()))
+            .expression_attribute_values(
+                ":ttl",
+                AttributeValue::N(expires_at.timestamp().to_string()),
+            )
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            // deleted in the meantime, or already touched further by another request.
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn set_data(&self, id: &str, data: &SessionData) -> Result<bool, AppError> {
+        let res = self
+            .ddb
+            .update_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .update_expression("SET #data = :data")
+            .condition_expression("attribute_exists(PK)")
+            .expression_attribute_names("#data", "data")
+            .expression_attribute_values(":data", data_to_attribute(data))
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn update_data(
+        &self,
+        id: &str,
+        changes: &SessionData,
+    ) -> Result<Option<SessionData>, AppError> {
+        // items written before session data existed have no map to merge into,
+        // the first merge creates it. A concurrent merge may create it first,
+        // in which case merging is tried again.
+        for _ in 0..2 {
+            match self.merge_data(id, changes).await {
+                Err(SdkError::ServiceError { err, .. }) if is_invalid_document_path(&err) => {}
+                res => return updated_data(res),
+            }
+            match self.create_data(id, changes).await {
+                Err(SdkError::ServiceError { err, .. })
+                    if err.is_conditional_check_failed_exception() => {}
+                res => return updated_data(res),
+            }
+        }
+
+        Err(AppError::Conflict(
+            "session data changed concurrently".to_owned(),
+        ))
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), AppError> {
+        self.ddb
+            .delete_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_if_owned(&self, id: &str, username: &str) -> Result<bool, AppError> {
+        let res = self
+            .ddb
+            .delete_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(id.to_owned()))
+            .condition_expression(
+                "#username = :username AND (attribute_not_exists(#kind) OR #kind <> :refresh)",
+            )
+            .expression_attribute_names("#username", "username")
+            .expression_attribute_names("#kind", "kind")
+            .expression_attribute_values(":username", AttributeValue::S(username.to_owned()))
+            .expression_attribute_values(":refresh", AttributeValue::S(REFRESH_KIND.to_owned()))
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            // missing, owned by someone else, or a refresh token.
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_user_sessions(&self, username: &str) -> Result<usize, AppError> {
+        let deleted = self.delete_user_items(username, |_| true).await?;
+
+        info!("{} sessions deleted for {}", deleted, username);
+        Ok(deleted)
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_family(&self, username: &str, family: &str) -> Result<usize, AppError> {
+        let deleted = self
+            .delete_user_items(username, |item| {
+                item.get_s("family").as_deref() == Some(family)
+            })
+            .await?;
+
+        info!("{} sessions of a family deleted for {}", deleted, username);
+        Ok(deleted)
+    }
+
+    #[instrument(skip(self))]
+    async fn list_user_sessions(
+        &self,
+        username: &str,
+        limit: usize,
+        start_after: Option<&str>,
+    ) -> Result<(Vec<Session>, Option<String>), AppError> {
+        // GSI1 has no sort key, so its cursor is fully determined by the last id.
+        let mut start_key = start_after.map(|id| {
+            HashMap::from([
+                ("PK".to_owned(), AttributeValue::S(id.to_owned())),
+                ("GSI1PK".to_owned(), AttributeValue::S(username.to_owned())),
+            ])
+        });
+
+        // refresh tokens share the index: query on until the page is full, asking
+        // for no more than it lacks so that the last key stays a valid cursor.
+        let mut sessions = Vec::new();
+        loop {
+            let missing = i32::try_from(limit - sessions.len()).unwrap_or(i32::MAX);
+            let (items, last_key) = self
+                .query_user_sessions_page(username, start_key, Some(missing), true)
+                .await?;
+            for item in items {
+                sessions.push(Session::try_from(item)?);
+            }
+
+            if sessions.len() >= limit || last_key.is_none() {
+                return Ok((sessions, last_key.and_then(|key| key.get_s("PK"))));
+            }
+            start_key = last_key;
+        }
+    }
+}
+
+impl From<&Session> for HashMap<String, AttributeValue> {
+    fn from(value: &Session) -> Self {
+        let mut retval = HashMap::new();
+        // Indexing attributes
+        retval.insert("PK".to_owned(), AttributeValue::S(value.id.to_owned()));
+        retval.insert(
+            "GSI1PK".to_owned(),
+            AttributeValue::S(value.username.to_owned()),
+        );
+        retval.insert(
+            "TTL".to_owned(),
+            AttributeValue::N(value.expires_at.timestamp().to_string()),
+        );
+        // Item attributes
+        retval.insert("id".to_owned(), AttributeValue::S(value.id.to_owned()));
+        retval.insert(
+            "created_at".to_owned(),
+            AttributeValue::S(value.created_at.to_rfc3339()),
+        );
+        retval.insert(
+            "expires_at".to_owned(),
+            AttributeValue::S(value.expires_at.to_rfc3339()),
+        );
+        retval.insert(
+            "username".to_owned(),
+            AttributeValue::S(value.username.to_owned()),
+        );
+        // string sets cannot be empty.
+        if !value.roles.is_empty() {
+            retval.insert(
+                "roles".to_owned(),
+                AttributeValue::Ss(value.roles.to_owned()),
+            );
+        }
+        if let Some(user_agent) = &value.client.user_agent {
+            retval.insert(
+                "user_agent".to_owned(),
+                AttributeValue::S(user_agent.to_owned()),
+            );
+        }
+        if let Some(ip_address) = &value.client.ip_address {
+            retval.insert(
+                "ip_address".to_owned(),
+                AttributeValue::S(ip_address.to_owned()),
+            );
+        }
+        retval.insert("data".to_owned(), data_to_attribute(&value.data));
+        if let Some(family) = &value.family {
+            retval.insert("family".to_owned(), AttributeValue::S(family.to_owned()));
+        }
+
+        retval
+    }
+}
+
+impl TryFrom<HashMap<String, AttributeValue>> for Session {
+    type Error = AppError;
+    fn try_from(value: HashMap<String, AttributeValue>) -> Result<Self, Self::Error> {
+        Ok(Session {
+            id: value.get_s("id").ok_or(AppError::backend("missing id"))?,
+            created_at: value
+                .get_dt("created_at")
+                .ok_or(AppError::backend("missing created_at date"))?,
+            expires_at: value
+                .get_dt("expires_at")
+                .ok_or(AppError::backend("missing expires_at date"))?,
+            username: value
+                .get_s("username")
+                .ok_or(AppError::backend("missing username"))?,
+            roles: value.get_ss("roles").unwrap_or_default(),
+            client: ClientMetadata {
+                user_agent: value.get_s("user_agent"),
+                ip_address: value.get_s("ip_address"),
+            },
+            data: data_from_item(&value),
+            family: value.get_s("family"),
+        })
+    }
+}
+
+impl From<&RefreshRecord> for HashMap<String, AttributeValue> {
+    fn from(value: &RefreshRecord) -> Self {
+        let mut retval = HashMap::from([
+            // Indexing attributes
+            ("PK".to_owned(), AttributeValue::S(value.id.to_owned())),
+            (
+                "GSI1PK".to_owned(),
+                AttributeValue::S(value.username.to_owned()),
+            ),
+            (
+                "TTL".to_owned(),
+                AttributeValue::N(value.expires_at.timestamp().to_string()),
+            ),
+            (
+                "kind".to_owned(),
+                AttributeValue::S(REFRESH_KIND.to_owned()),
+            ),
+            // Item attributes
+            ("id".to_owned(), AttributeValue::S(value.id.to_owned())),
+            (
+                "session_id".to_owned(),
+                AttributeValue::S(value.session_id.to_owned()),
+            ),
+            (
+                "family".to_owned(),
+                AttributeValue::S(value.family.to_owned()),
+            ),
+            (
+                "username".to_owned(),
+                AttributeValue::S(value.username.to_owned()),
+            ),
+            (
+                "expires_at".to_owned(),
+                AttributeValue::S(value.expires_at.to_rfc3339()),
+            ),
+            ("rotated".to_owned(), AttributeValue::Bool(value.rotated)),
+        ]);
+        if !value.roles.is_empty() {
+            retval.insert(
+                "roles".to_owned(),
+                AttributeValue::Ss(value.roles.to_owned()),
+            );
+        }
+
+        retval
+    }
+}
+
+impl TryFrom<HashMap<String, AttributeValue>> for RefreshRecord {
+    type Error = AppError;
+    fn try_from(value: HashMap<String, AttributeValue>) -> Result<Self, Self::Error> {
+        Ok(RefreshRecord {
+            id: value.get_s("id").ok_or(AppError::backend("missing id"))?,
+            session_id: value
+                .get_s("session_id")
+                .ok_or(AppError::backend("missing session_id"))?,
+            family: value
+                .get_s("family")
+                .ok_or(AppError::backend("missing family"))?,
+            username: value
+                .get_s("username")
+                .ok_or(AppError::backend("missing username"))?,
+            roles: value.get_ss("roles").unwrap_or_default(),
+            expires_at: value
+                .get_dt("expires_at")
+                .ok_or(AppError::backend("missing expires_at date"))?,
+            rotated: matches!(value.get("rotated"), Some(AttributeValue::Bool(true))),
+        })
+    }
+}
+
+fn is_refresh(item: &Item) -> bool {
+    item.get_s("kind").as_deref() == Some(REFRESH_KIND)
+}
+
+/// The session stored as `item`. Refresh tokens share the table, but are not
+/// sessions.
+fn session_from_item(item: Option<Item>) -> Result<Option<Session>, AppError> {
+    match item {
+        Some(item) if !is_refresh(&item) => Ok(Some(item.try_into()?)),
+        _ => Ok(None),
+    }
+}
+
+/// Whether a transaction was canceled by one of its conditions, or by a
+/// concurrent transaction, rather than by throttling.
+fn lost_race(err: &TransactWriteItemsError) -> bool {
+    match &err.kind {
+        TransactWriteItemsErrorKind::TransactionCanceledException(canceled) => canceled
+            .cancellation_reasons()
+            .unwrap_or_default()
+            .iter()
+            .any(|reason| {
+                matches!(
+                    reason.code(),
+                    Some("ConditionalCheckFailed") | Some("TransactionConflict")
+                )
+            }),
+        _ => false,
+    }
+}
+
+/// The data of the session a data update returned, `None` if it is missing.
+fn updated_data(
+    res: Result<UpdateItemOutput, SdkError<UpdateItemError>>,
+) -> Result<Option<SessionData>, AppError> {
+    match res {
+        Ok(res) => Ok(Some(
+            res.attributes
+                .as_ref()
+                .map(data_from_item)
+                .unwrap_or_default(),
+        )),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Whether an update failed because it reaches into a map the item lacks.
+fn is_invalid_document_path(err: &UpdateItemError) -> bool {
+    err.code() == Some("ValidationException")
+        && err
+            .message()
+            .is_some_and(|message| message.contains("document path"))
+}
+
+/// `changes` merged into empty data: everything but the removals.
+fn without_nulls(changes: &SessionData) -> SessionData {
+    changes
+        .iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
+
+fn data_to_attribute(data: &SessionData) -> AttributeValue {
+    AttributeValue::M(
+        data.iter()
+            .map(|(key, value)| (key.to_owned(), json_to_attribute(value)))
+            .collect(),
+    )
+}
+
+/// Read the `data` attribute of an item. Items written before session data
+/// existed have none, which reads as empty.
+fn data_from_item(item: &HashMap<String, AttributeValue>) -> SessionData {
+    match item.get("data") {
+        Some(AttributeValue::M(map)) => map
+            .iter()
+            .filter_map(|(key, value)| Some((key.to_owned(), attribute_to_json(value)?)))
+            .collect(),
+        _ => SessionData::new(),
+    }
+}
+
+fn json_to_attribute(value: &Value) -> AttributeValue {
+    match value {
+        Value::Null => AttributeValue::Null(true),
+        Value::Bool(b) => AttributeValue::Bool(*b),
+        Value::Number(n) => AttributeValue::N(n.to_string()),
+        Value::String(s) => AttributeValue::S(s.to_owned()),
+        Value::Array(items) => AttributeValue::L(items.iter().map(json_to_attribute).collect()),
+        Value::Object(map) => AttributeValue::M(
+            map.iter()
+                .map(|(key, value)| (key.to_owned(), json_to_attribute(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert an attribute back to JSON. Binary attributes have no JSON
+/// counterpart and are skipped.
+fn attribute_to_json(value: &AttributeValue) -> Option<Value> {
+    match value {
+        AttributeValue::Null(_) => Some(Value::Null),
+        AttributeValue::Bool(b) => Some(Value::Bool(*b)),
+        AttributeValue::N(n) => n.parse::<Number>().ok().map(Value::Number),
+        AttributeValue::S(s) => Some(Value::String(s.to_owned())),
+        AttributeValue::L(items) => Some(Value::Array(
+            items.iter().filter_map(attribute_to_json).collect(),
+        )),
+        AttributeValue::M(map) => Some(Value::Object(
+            map.iter()
+                .filter_map(|(key, value)| Some((key.to_owned(), attribute_to_json(value)?)))
+                .collect(),
+        )),
+        AttributeValue::Ss(values) => Some(Value::from(values.to_owned())),
+        AttributeValue::Ns(values) => Some(Value::Array(
+            values
+                .iter()
+                .filter_map(|n| n.parse::<Number>().ok().map(Value::Number))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn session_item_round_trip() {
+        let created_at: DateTime<Utc> = "2022-09-01T12:00:00Z".parse().unwrap();
+        let session = Session::new(
+            "id".to_owned(),
+            "alice".to_owned(),
+            created_at,
+            created_at + Duration::days(7),
+        );
+
+        let item: HashMap<String, AttributeValue> = (&session).into();
+        assert_eq!(item.get_s("PK"), Some("id".to_owned()));
+        assert_eq!(item.get_s("GSI1PK"), Some("alice".to_owned()));
+        assert_eq!(
+            item.get_n("TTL"),
+            Some(
+                "2022-09-08T12:00:00Z"
+                    .parse::<DateTime<Utc>>()
+                    .unwrap()
+                    .timestamp() as f64
+            )
+        );
+
+        assert!(!item.contains_key("roles"));
+
+        let parsed = Session::try_from(item).unwrap();
+        assert_eq!(parsed.expires_at, session.expires_at);
+        assert_eq!(parsed.created_at, session.created_at);
+        assert!(parsed.roles.is_empty());
+        assert!(parsed.data.is_empty());
+
+        let mut session = session;
+        session.roles = vec!["admin".to_owned(), "billing".to_owned()];
+        let parsed = Session::try_from(HashMap::from(&session)).unwrap();
+        assert_eq!(parsed.roles, session.roles);
+    }
+
+    #[test]
+    fn refresh_item_round_trip() {
+        let refresh = RefreshRecord {
+            id: "r".to_owned(),
+            session_id: "id".to_owned(),
+            family: "first".to_owned(),
+            username: "alice".to_owned(),
+            roles: vec!["admin".to_owned()],
+            expires_at: "2022-09-01T12:00:00Z".parse().unwrap(),
+            rotated: true,
+        };
+
+        let item: HashMap<String, AttributeValue> = (&refresh).into();
+        assert_eq!(item.get_s("GSI1PK"), Some("alice".to_owned()));
+        assert!(is_refresh(&item));
+        assert_eq!(RefreshRecord::try_from(item).unwrap(), refresh);
+
+        let session = Session::new(
+            "id".to_owned(),
+            "alice".to_owned(),
+            refresh.expires_at,
+            refresh.expires_at,
+        );
+        assert!(!is_refresh(&(&session).into()));
+        let found = session_from_item(Some((&session).into())).unwrap();
+        assert_eq!(found.map(|found| found.id), Some(session.id));
+        assert!(session_from_item(Some((&refresh).into()))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn session_data_round_trip() {
+        let data: SessionData = serde_json::from_value(serde_json::json!({
+            "roles": ["admin", "billing"],
+            "tenant": { "id": 42, "name": "acme", "trial": false },
+            "cart": null,
+            "ratio": 0.5,
+        }))
+        .unwrap();
+
+        let item = HashMap::from([("data".to_owned(), data_to_attribute(&data))]);
+        assert_eq!(data_from_item(&item), data);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(1), time::Duration::from_millis(100));
+        assert_eq!(backoff(2), time::Duration::from_millis(200));
+        assert_eq!(backoff(4), time::Duration::from_millis(800));
+    }
+
+    #[test]
+    fn missing_data_reads_as_empty() {
+        assert!(data_from_item(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn first_merge_creates_the_data_map() {
+        // an item written before session data existed.
+        let mut item: HashMap<String, AttributeValue> = (&Session::new(
+            "id".to_owned(),
+            "alice".to_owned(),
+            Utc::now(),
+            Utc::now() + Duration::days(1),
+        ))
+            .into();
+        item.remove("data");
+
+        let changes: SessionData = serde_json::from_value(serde_json::json!({
+            "tenant": "acme",
+            "cart": null,
+        }))
+        .unwrap();
+        item.insert(
+            "data".to_owned(),
+            data_to_attribute(&without_nulls(&changes)),
+        );
+
+        let session = Session::try_from(item).unwrap();
+        assert_eq!(session.get::<String>("tenant"), Some("acme".to_owned()));
+        assert_eq!(session.data.len(), 1);
+    }
+}
@@ -0,0 +1,416 @@
+//! # In-memory implementation of `SessionBackend`.
+//!
+//! Meant for tests and local development. Items live in a `HashMap` keyed by
+//! session id, with a username index standing in for `GSI1`. Like the DynamoDB
+//! table, expired items are not swept on their own: they stay around until they
+//! are deleted, and it is up to `SessionStore` to ignore them.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use crate::{
+    errors::AppError,
+    store::{RefreshRecord, Session, SessionData},
+};
+
+use super::SessionBackend;
+
+#[derive(Default)]
+pub struct InMemoryBackend {
+    tables: RwLock<Tables>,
+}
+
+#[derive(Default)]
+struct Tables {
+    /// primary table, keyed like `PK`.
+    items: HashMap<String, Session>,
+    /// secondary index, keyed like `GSI1PK`. Refresh tokens are indexed too.
+    gsi1: HashMap<String, HashSet<String>>,
+    /// refresh tokens, which live in the primary table in DynamoDB.
+    refresh: HashMap<String, RefreshRecord>,
+}
+
+impl Tables {
+    fn put(&mut self, session: &Session) {
+        // a put overwrites any previous item under the same key, index included.
+        if let Some(previous) = self.items.insert(session.id.clone(), session.clone()) {
+            if let Some(ids) = self.gsi1.get_mut(&previous.username) {
+                ids.remove(&previous.id);
+            }
+        }
+        self.gsi1
+            .entry(session.username.clone())
+            .or_default()
+            .insert(session.id.clone());
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Session> {
+        let session = self.items.remove(id)?;
+        if let Some(ids) = self.gsi1.get_mut(&session.username) {
+            ids.remove(id);
+        }
+        Some(session)
+    }
+
+    fn put_refresh(&mut self, refresh: &RefreshRecord) {
+        self.gsi1
+            .entry(refresh.username.clone())
+            .or_default()
+            .insert(refresh.id.clone());
+        self.refresh.insert(refresh.id.clone(), refresh.clone());
+    }
+
+    fn remove_refresh(&mut self, id: &str) {
+        if let Some(refresh) = self.refresh.remove(id) {
+            if let Some(ids) = self.gsi1.get_mut(&refresh.username) {
+                ids.remove(id);
+            }
+        }
+    }
+}
+
+impl InMemoryBackend {
+    pub fn new() -> InMemoryBackend {
+        InMemoryBackend::default()
+    }
+
+    /// Number of stored sessions, expired ones included.
+    pub fn len(&self) -> usize {
+        self.tables.read().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl SessionBackend for InMemoryBackend {
+    async fn get(&self, id: &str) -> Result<Option<Session>, AppError> {
+        Ok(self.tables.read().unwrap().items.get(id).cloned())
+    }
+
+    async fn create(&self, session: &Session) -> Result<(), AppError> {
+        self.tables.write().unwrap().put(session);
+        Ok(())
+    }
+
+    async fn create_with_refresh(
+        &self,
+        session: &Session,
+        refresh: &RefreshRecord,
+    ) -> Result<(), AppError> {
+        let mut tables = self.tables.write().unwrap();
+        tables.put(session);
+        tables.put_refresh(refresh);
+        Ok(())
+    }
+
+    async fn get_refresh(&self, id: &str) -> Result<Option<RefreshRecord>, AppError> {
+        Ok(self.tables.read().unwrap().refresh.get(id).cloned())
+    }
+
+    async fn rotate(
+        &self,
+        old: &RefreshRecord,
+        session: &Session,
+        refresh: &RefreshRecord,
+    ) -> Result<(), AppError> {
+        let mut tables = self.tables.write().unwrap();
+        match tables.refresh.get_mut(&old.id) {
+            Some(stored) if !stored.rotated => stored.rotated = true,
+            _ => {
+                return Err(AppError::Conflict(
+                    "refresh token already rotated".to_owned(),
+                ))
+            }
+        }
+        tables.remove(&old.session_id);
+        tables.put(session);
+        tables.put_refresh(refresh);
+        Ok(())
+    }
+
+    async fn touch(&self, id: &str, expires_at: DateTime<Utc>) -> Result<bool, AppError> {
+        let mut tables = self.tables.write().unwrap();
+        match tables.items.get_mut(id) {
+            // compared at the granularity of the `TTL` attribute, like DynamoDB.
+            Some(session) if session.expires_at.timestamp() < expires_at.timestamp() => {
+                session.expires_at = expires_at;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn set_data(&self, id: &str, data: &SessionData) -> Result<bool, AppError> {
+        let mut tables = self.tables.write().unwrap();
+        match tables.items.get_mut(id) {
+            Some(session) => {
+                session.data = data.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn update_data(
+        &self,
+        id: &str,
+        changes: &SessionData,
+    ) -> Result<Option<SessionData>, AppError> {
+        let mut tables = self.tables.write().unwrap();
+        let session = match tables.items.get_mut(id) {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        for (key, value) in changes {
+            if value.is_null() {
+                session.data.remove(key);
+            } else {
+                session.data.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(Some(session.data.clone()))
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), AppError> {
+        self.tables.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn delete_if_owned(&self, id: &str, username: &str) -> Result<bool, AppError> {
+        let mut tables = self.tables.write().unwrap();
+        match tables.items.get(id) {
+            Some(session) if session.username == username => {}
+            _ => return Ok(false),
+        }
+        tables.remove(id);
+
+        Ok(true)
+    }
+
+    async fn delete_user_sessions(&self, username: &str) -> Result<usize, AppError> {
+        let mut tables = self.tables.write().unwrap();
+        let ids = tables.gsi1.remove(username).unwrap_or_default();
+        let mut deleted = 0;
+        for id in &ids {
+            if tables.items.remove(id).is_some() {
+                deleted += 1;
+            }
+            tables.refresh.remove(id);
+        }
+
+        Ok(deleted)
+    }
+
+    async fn delete_family(&self, username: &str, family: &str) -> Result<usize, AppError> {
+        let mut tables = self.tables.write().unwrap();
+        let ids: Vec<String> = tables
+            .items
+            .values()
+            .filter(|session| {
+                session.username == username && session.family.as_deref() == Some(family)
+            })
+            .map(|session| session.id.clone())
+            .collect();
+        for id in &ids {
+            tables.remove(id);
+        }
+        let refresh_ids: Vec<String> = tables
+            .refresh
+            .values()
+            .filter(|refresh| refresh.username == username && refresh.family == family)
+            .map(|refresh| refresh.id.clone())
+            .collect();
+        for id in &refresh_ids {
+            tables.remove_refresh(id);
+        }
+
+        Ok(ids.len())
+    }
+
+    async fn list_user_sessions(
+        &self,
+        username: &str,
+        limit: usize,
+        start_after: Option<&str>,
+    ) -> Result<(Vec<Session>, Option<String>), AppError> {
+        let tables = self.tables.read().unwrap();
+        let mut ids: Vec<&String> = match tables.gsi1.get(username) {
+            Some(ids) => ids.iter().collect(),
+            None => return Ok((Vec::new(), None)),
+        };
+        // any stable order will do to resume after a cursor. GSI1 has no sort
+        // key, DynamoDB promises no particular order, and callers must not
+        // rely on this one.
+        ids.sort();
+        // refresh tokens are skipped without counting towards `limit`.
+        let remaining: Vec<&Session> = ids
+            .into_iter()
+            .filter(|id| match start_after {
+                Some(start) => id.as_str() > start,
+                None => true,
+            })
+            .filter_map(|id| tables.items.get(id))
+            .collect();
+
+        let sessions: Vec<Session> = remaining.iter().take(limit).copied().cloned().collect();
+        let last_key = if remaining.len() > limit {
+            sessions.last().map(|session| session.id.clone())
+        } else {
+            None
+        };
+
+        Ok((sessions, last_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn session(id: &str, username: &str) -> Session {
+        let created_at = Utc::now();
+        Session::new(
+            id.to_owned(),
+            username.to_owned(),
+            created_at,
+            created_at + Duration::days(1),
+        )
+    }
+
+    #[tokio::test]
+    async fn get_returns_created_session() {
+        let backend = InMemoryBackend::new();
+        backend.create(&session("a", "alice")).await.unwrap();
+
+        let found = backend
+            .get("a")
+            .await
+            .unwrap()
+            .expect("session should exist");
+        assert_eq!(found.username, "alice");
+        assert!(backend.get("b").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn delete_user_sessions_only_touches_that_user() {
+        let backend = InMemoryBackend::new();
+        backend.create(&session("a", "alice")).await.unwrap();
+        backend.create(&session("b", "alice")).await.unwrap();
+        backend.create(&session("c", "bob")).await.unwrap();
+
+        assert_eq!(backend.delete_user_sessions("alice").await.unwrap(), 2);
+
+        assert!(backend.get("a").await.unwrap().is_none());
+        assert!(backend.get("b").await.unwrap().is_none());
+        assert!(backend.get("c").await.unwrap().is_some());
+        let (sessions, _) = backend.list_user_sessions("alice", 10, None).await.unwrap();
+        assert!(sessions.is_empty());
+        assert_eq!(backend.delete_user_sessions("alice").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn delete_if_owned_checks_the_owner() {
+        let backend = InMemoryBackend::new();
+        backend.create(&session("a", "alice")).await.unwrap();
+
+        assert!(!backend.delete_if_owned("a", "bob").await.unwrap());
+        assert!(backend.get("a").await.unwrap().is_some());
+        assert!(backend.delete_if_owned("a", "alice").await.unwrap());
+        assert!(backend.get("a").await.unwrap().is_none());
+        assert!(!backend.delete_if_owned("a", "alice").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn overwrite_moves_index_entry() {
+        let backend = InMemoryBackend::new();
+        backend.create(&session("a", "alice")).await.unwrap();
+        backend.create(&session("a", "bob")).await.unwrap();
+
+        let (sessions, _) = backend.list_user_sessions("alice", 10, None).await.unwrap();
+        assert!(sessions.is_empty());
+        let (sessions, _) = backend.list_user_sessions("bob", 10, None).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(backend.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_user_sessions_pages() {
+        let backend = InMemoryBackend::new();
+        for id in ["a", "b", "c"] {
+            backend.create(&session(id, "alice")).await.unwrap();
+        }
+
+        let (first, last_key) = backend.list_user_sessions("alice", 2, None).await.unwrap();
+        assert_eq!(first.len(), 2);
+        let (second, last_key) = backend
+            .list_user_sessions("alice", 2, last_key.as_deref())
+            .await
+            .unwrap();
+        assert_eq!(second.len(), 1);
+        assert!(last_key.is_none());
+
+        assert_eq!(ids(first.iter().chain(&second)), ids_of(&["a", "b", "c"]));
+    }
+
+    fn ids<'a>(sessions: impl IntoIterator<Item = &'a Session>) -> HashSet<String> {
+        sessions
+            .into_iter()
+            .map(|session| session.id.clone())
+            .collect()
+    }
+
+    fn ids_of(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn refresh(id: &str, session: &Session) -> RefreshRecord {
+        RefreshRecord {
+            id: id.to_owned(),
+            session_id: session.id.clone(),
+            family: session.id.clone(),
+            username: session.username.clone(),
+            roles: Vec::new(),
+            expires_at: session.expires_at,
+            rotated: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_share_the_index_without_shortening_pages() {
+        let backend = InMemoryBackend::new();
+        for (id, refresh_id) in [("b", "a"), ("d", "c"), ("f", "e")] {
+            let mut created = session(id, "alice");
+            created.family = Some(id.to_owned());
+            backend
+                .create_with_refresh(&created, &refresh(refresh_id, &created))
+                .await
+                .unwrap();
+        }
+
+        let (first, last_key) = backend.list_user_sessions("alice", 2, None).await.unwrap();
+        assert_eq!(first.len(), 2);
+        let (second, last_key) = backend
+            .list_user_sessions("alice", 2, last_key.as_deref())
+            .await
+            .unwrap();
+        assert_eq!(second.len(), 1);
+        assert!(last_key.is_none());
+        assert_eq!(ids(first.iter().chain(&second)), ids_of(&["b", "d", "f"]));
+
+        assert_eq!(backend.delete_family("alice", "b").await.unwrap(), 1);
+        assert!(backend.get_refresh("a").await.unwrap().is_none());
+        assert_eq!(backend.delete_user_sessions("alice").await.unwrap(), 2);
+        assert!(backend.get_refresh("c").await.unwrap().is_none());
+        assert!(backend.is_empty());
+    }
+}
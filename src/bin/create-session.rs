@@ -1,23 +1,20 @@
-use std::{env, collections::HashSet};
-
+use std::{env, sync::Arc};
 
 use aws_sdk_dynamodb::Client;
-use ddb_session_store::{utils::{setup_sdk_config, setup_tracing}, api, store::SessionStore};
+use ddb_session_store::{
+    alb::extract::Handler,
+    api,
+    backend::DynamoDbBackend,
+    config::SessionConfig,
+    credentials::{self, CredentialVerifier},
+    store::SessionStore,
+    utils::{setup_sdk_config, setup_tracing},
+};
 use lambda_http::{service_fn, Request};
 use tracing::{info, instrument};
-use lazy_static::lazy_static;
 
 type E = Box<dyn std::error::Error + Sync + Send + 'static>;
 
-lazy_static! {
-    static ref PASSWORDS: HashSet<String> = HashSet::from([
-        "pingpong".to_owned(),
-        "moultipass".to_owned(),
-        "devoid of meaning".to_owned(),
-        "perlimpinpin".to_owned()
-    ]);
-}
-
 #[instrument]
 #[tokio::main]
 async fn main() -> Result<(), E> {
@@ -25,14 +22,24 @@ async fn main() -> Result<(), E> {
 
     let config = setup_sdk_config().await;
     let ddb = Client::new(&config);
-    let store = SessionStore::new(
-        &ddb,
-        env::var("TABLE_NAME")
-            .to_owned()
-            .expect("TABLE_NAME must be set"),
+    let secrets = aws_sdk_secretsmanager::Client::new(&config);
+    let store = Arc::new(
+        SessionStore::new(
+            &ddb,
+            env::var("TABLE_NAME")
+                .to_owned()
+                .expect("TABLE_NAME must be set"),
+        )
+        .with_config(SessionConfig::from_env(&secrets).await?),
     );
-    lambda_http::run(service_fn(|event: Request| api::create_session(&store, event))).await?;
+    let verifier: Arc<dyn CredentialVerifier> = credentials::from_env(&ddb)?.into();
+    lambda_http::run(service_fn(move |mut event: Request| {
+        event.extensions_mut().insert(store.clone());
+        event.extensions_mut().insert(verifier.clone());
+        Handler::call(&api::create_session::<DynamoDbBackend>, event)
+    }))
+    .await?;
     info!("execution started");
 
     Ok(())
-}
\ No newline at end of file
+}
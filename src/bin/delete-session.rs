@@ -0,0 +1,42 @@
+use std::{env, sync::Arc};
+
+use aws_sdk_dynamodb::Client;
+use ddb_session_store::{
+    alb::extract::Handler,
+    api,
+    backend::DynamoDbBackend,
+    config::SessionConfig,
+    store::SessionStore,
+    utils::{setup_sdk_config, setup_tracing},
+};
+use lambda_http::{service_fn, Request};
+use tracing::{info, instrument};
+
+type E = Box<dyn std::error::Error + Sync + Send + 'static>;
+
+#[instrument]
+#[tokio::main]
+async fn main() -> Result<(), E> {
+    setup_tracing();
+
+    let config = setup_sdk_config().await;
+    let ddb = Client::new(&config);
+    let secrets = aws_sdk_secretsmanager::Client::new(&config);
+    let store = Arc::new(
+        SessionStore::new(
+            &ddb,
+            env::var("TABLE_NAME")
+                .to_owned()
+                .expect("TABLE_NAME must be set"),
+        )
+        .with_config(SessionConfig::from_env(&secrets).await?),
+    );
+    lambda_http::run(service_fn(move |mut event: Request| {
+        event.extensions_mut().insert(store.clone());
+        Handler::call(&api::delete_session::<DynamoDbBackend>, event)
+    }))
+    .await?;
+    info!("execution started");
+
+    Ok(())
+}
@@ -1,7 +1,14 @@
-use std::env;
+use std::{env, sync::Arc};
 
 use aws_sdk_dynamodb::Client;
-use ddb_session_store::{utils::{setup_sdk_config, setup_tracing}, api, store::SessionStore};
+use ddb_session_store::{
+    alb::extract::Handler,
+    api,
+    backend::DynamoDbBackend,
+    config::SessionConfig,
+    store::SessionStore,
+    utils::{setup_sdk_config, setup_tracing},
+};
 use lambda_http::{service_fn, Request};
 use tracing::{info, instrument};
 
@@ -14,9 +21,22 @@ async fn main() -> Result<(), E> {
 
     let config = setup_sdk_config().await;
     let ddb = Client::new(&config);
-    let  store = SessionStore::new(&ddb, env::var("TABLE_NAME").to_owned().expect("TABLE_NAME must be set"));
-    lambda_http::run(service_fn(|event: Request| api::get_session(&store, event))).await?;
+    let secrets = aws_sdk_secretsmanager::Client::new(&config);
+    let store = Arc::new(
+        SessionStore::new(
+            &ddb,
+            env::var("TABLE_NAME")
+                .to_owned()
+                .expect("TABLE_NAME must be set"),
+        )
+        .with_config(SessionConfig::from_env(&secrets).await?),
+    );
+    lambda_http::run(service_fn(move |mut event: Request| {
+        event.extensions_mut().insert(store.clone());
+        Handler::call(&api::get_session::<DynamoDbBackend>, event)
+    }))
+    .await?;
     info!("execution started");
-    
+
     Ok(())
 }
@@ -1,13 +1,14 @@
-use std::env;
+use std::{env, sync::Arc};
 
 use aws_sdk_dynamodb::Client;
 use ddb_session_store::{
-    alb::AlbRouter,
+    alb::{from_fn, middleware},
     api,
-    utils::{setup_sdk_config, setup_tracing}, store::SessionStore,
+    config::SessionConfig,
+    credentials::{self, CredentialVerifier},
+    store::SessionStore,
+    utils::{setup_sdk_config, setup_tracing},
 };
-use http::Method;
-use lambda_http::{service_fn, Request};
 use tracing::{info, instrument};
 
 type E = Box<dyn std::error::Error + Sync + Send + 'static>;
@@ -19,24 +20,24 @@ async fn main() -> Result<(), E> {
 
     let config = setup_sdk_config().await;
     let ddb = Client::new(&config);
-    let store = SessionStore::new(
-        &ddb,
-        env::var("TABLE_NAME")
-            .to_owned()
-            .expect("TABLE_NAME must be set"),
+    let secrets = aws_sdk_secretsmanager::Client::new(&config);
+    let store = Arc::new(
+        SessionStore::new(
+            &ddb,
+            env::var("TABLE_NAME")
+                .to_owned()
+                .expect("TABLE_NAME must be set"),
+        )
+        .with_config(SessionConfig::from_env(&secrets).await?),
     );
+    let verifier: Arc<dyn CredentialVerifier> = credentials::from_env(&ddb)?.into();
 
-    let mut router = AlbRouter::new();
-    router.insert(Method::GET, "/", |r| api::health_check(&store, r))?;
-    router.insert(Method::GET, "/sessions", |r| api::get_session(&store, r))?;
-    router.insert(Method::POST, "/sessions", |r| {
-        api::create_session(&store, r)
-    })?;
-    router.insert(Method::DELETE, "/sessions/:username", |r| {
-        api::delete_user_sessions(&store, r)
-    })?;
+    let mut router = api::router(store, verifier)?;
+    router.layer(from_fn(middleware::request_ids));
+    router.layer(from_fn(middleware::trace));
+    router.layer(from_fn(middleware::map_errors));
 
-    lambda_http::run(service_fn(|request: Request| router.handle(request))).await?;
+    lambda_http::run(router).await?;
     info!("execution started");
 
     Ok(())
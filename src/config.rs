@@ -0,0 +1,670 @@
+//! # Runtime configuration for `SessionStore`.
+
+use std::env;
+
+use aws_sdk_secretsmanager::Client as SecretsClient;
+use chrono::Duration;
+
+use crate::{errors::AppError, hashing::HashKeys, jwt::JwtKeys};
+
+/// Settings applied by `SessionStore` on top of its backend.
+///
+/// ```
+/// use chrono::Duration;
+/// use ddb_session_store::config::SessionConfig;
+///
+/// let config = SessionConfig::new()
+///     .with_ttl(Duration::hours(12))
+///     .with_delete_expired(true);
+/// assert_eq!(config.ttl(), Duration::hours(12));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    ttl: Duration,
+    delete_expired: bool,
+    sliding: Option<SlidingExpiration>,
+    cookie: Option<SessionCookie>,
+    hash_keys: HashKeys,
+    signed_tokens: Option<SignedTokens>,
+    jwt: Option<JwtConfig>,
+    refresh_ttl: Option<Duration>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl: Duration::days(7),
+            delete_expired: false,
+            sliding: None,
+            cookie: None,
+            hash_keys: HashKeys::default(),
+            signed_tokens: None,
+            jwt: None,
+            refresh_ttl: None,
+        }
+    }
+}
+
+/// Idle timeout settings for sessions that are kept alive by their use.
+///
+/// A sliding session expires `idle_timeout` after it was last touched, but
+/// never later than `ttl` after its creation: the session `ttl` acts as the
+/// absolute cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingExpiration {
+    idle_timeout: Duration,
+    touch_interval: Duration,
+}
+
+impl SlidingExpiration {
+    /// `touch_interval` throttles writes: a session is only touched when that
+    /// would push its expiry forward by at least this much.
+    pub fn new(idle_timeout: Duration, touch_interval: Duration) -> SlidingExpiration {
+        SlidingExpiration {
+            idle_timeout,
+            touch_interval,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn touch_interval(&self) -> Duration {
+        self.touch_interval
+    }
+}
+
+/// Stateless session tokens for read-heavy services.
+///
+/// Sessions are still stored, but `create` hands out a token signed with
+/// `keys` that carries the session id, its user and its expiry. Reading such a
+/// token only costs a lookup of the session, which fails once it was revoked.
+/// That check is skipped for `max_staleness` after it last passed, so with a
+/// non-zero staleness a revoked session may keep working for that long. The
+/// session is then served as it was read: its data and expiry may lag behind
+/// writes made through other instances for as long.
+#[derive(Debug, Clone)]
+pub struct SignedTokens {
+    keys: HashKeys,
+    max_staleness: Duration,
+}
+
+impl SignedTokens {
+    /// Sign tokens with `keys`, checking every read against the table.
+    pub fn new(keys: HashKeys) -> SignedTokens {
+        SignedTokens {
+            keys,
+            max_staleness: Duration::zero(),
+        }
+    }
+
+    /// Trust a session for `max_staleness` after its last revocation check.
+    pub fn with_max_staleness(mut self, max_staleness: Duration) -> SignedTokens {
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    pub fn keys(&self) -> &HashKeys {
+        &self.keys
+    }
+
+    pub fn max_staleness(&self) -> Duration {
+        self.max_staleness
+    }
+}
+
+/// Settings of the JWTs sessions can be exchanged for, see `jwt`.
+///
+/// JWTs cannot be revoked, which their short lifetime, five minutes by
+/// default, makes up for.
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    keys: JwtKeys,
+    lifetime: Duration,
+    issuer: Option<String>,
+}
+
+impl JwtConfig {
+    pub fn new(keys: JwtKeys) -> JwtConfig {
+        JwtConfig {
+            keys,
+            lifetime: Duration::minutes(5),
+            issuer: None,
+        }
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> JwtConfig {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Set the `iss` claim, left out by default.
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> JwtConfig {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn keys(&self) -> &JwtKeys {
+        &self.keys
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+}
+
+/// Cookie carrying the session id for browser clients, which cannot set an
+/// `Authorization` header themselves.
+///
+/// The cookie is `HttpOnly`, `Secure` and `SameSite=Lax` on path `/` unless
+/// told otherwise. Browsers drop `SameSite=None` cookies that are not `Secure`,
+/// see `validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionCookie {
+    name: String,
+    http_only: bool,
+    secure: bool,
+    same_site: SameSite,
+    domain: Option<String>,
+    path: String,
+}
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl SessionCookie {
+    pub fn new(name: impl Into<String>) -> SessionCookie {
+        SessionCookie {
+            name: name.into(),
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            domain: None,
+            path: "/".to_owned(),
+        }
+    }
+
+    pub fn with_http_only(mut self, http_only: bool) -> SessionCookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn with_secure(mut self, secure: bool) -> SessionCookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> SessionCookie {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Share the cookie with the subdomains of `domain`. By default the cookie
+    /// is only sent back to the host that set it.
+    pub fn with_domain(mut self, domain: impl Into<String>) -> SessionCookie {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> SessionCookie {
+        self.path = path.into();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn same_site(&self) -> SameSite {
+        self.same_site
+    }
+
+    /// Turn down attributes browsers would reject the cookie for.
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.same_site == SameSite::None && !self.secure {
+            return Err(AppError::Validation(
+                "SameSite=None cookies must be Secure".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `Set-Cookie` value storing `session_id` for `max_age`, rounded up to
+    /// the second.
+    pub fn set(&self, session_id: &str, max_age: Duration) -> String {
+        let mut seconds = max_age.num_seconds();
+        if max_age > Duration::seconds(seconds) {
+            seconds += 1;
+        }
+        self.header(session_id, seconds.max(0))
+    }
+
+    /// `Set-Cookie` value making the browser forget the cookie.
+    pub fn clear(&self) -> String {
+        self.header("", 0)
+    }
+
+    fn header(&self, value: &str, max_age: i64) -> String {
+        let mut header = format!(
+            "{}={}; Max-Age={}; Path={}",
+            self.name, value, max_age, self.path
+        );
+        if let Some(domain) = &self.domain {
+            header.push_str(&format!("; Domain={}", domain));
+        }
+        header.push_str(&format!("; SameSite={}", self.same_site.as_str()));
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        header
+    }
+}
+
+impl SessionConfig {
+    pub fn new() -> SessionConfig {
+        SessionConfig::default()
+    }
+
+    /// Build a configuration from the environment, falling back to the defaults
+    /// for unset variables but `SESSION_HASH_KEYS`.
+    ///
+    /// * `SESSION_TTL`: session lifetime, see `parse_duration` for the format.
+    /// * `SESSION_DELETE_EXPIRED`: `true` to delete expired sessions on read.
+    /// * `SESSION_IDLE_TIMEOUT`: enables sliding expiration with that idle window.
+    /// * `SESSION_TOUCH_INTERVAL`: minimum expiry extension worth a write when
+    ///   sliding, one minute by default.
+    /// * `SESSION_COOKIE_NAME`: also accept the session id from that cookie, and
+    ///   set it when creating sessions.
+    /// * `SESSION_COOKIE_DOMAIN`, `SESSION_COOKIE_PATH`: scope of the cookie.
+    /// * `SESSION_COOKIE_SAME_SITE`: `strict`, `lax` or `none`, the latter
+    ///   requiring a `Secure` cookie.
+    /// * `SESSION_COOKIE_SECURE`, `SESSION_COOKIE_HTTP_ONLY`: `false` to drop
+    ///   these attributes.
+    /// * `SESSION_HASH_KEYS_SECRET_ARN`: Secrets Manager secret holding the
+    ///   secrets hashing session ids at rest, fetched with `secrets`. Its value
+    ///   is in the format of `HashKeys::parse`, or a bare secret for the key
+    ///   `k1` as Secrets Manager generates them.
+    /// * `SESSION_HASH_KEYS`: the hash keys themselves, for local runs. One of
+    ///   the two is required.
+    /// * `SESSION_SIGNING_KEYS`: hand out signed tokens, see `SignedTokens`,
+    ///   with these keys in the format of `SESSION_HASH_KEYS`.
+    /// * `SESSION_REVOCATION_STALENESS`: how long a signed token is trusted
+    ///   after its last revocation check, none by default.
+    /// * `SESSION_JWT_KEYS`: enables `POST /sessions/token`, signing with these
+    ///   keys, see `JwtKeys::load` for the format.
+    /// * `SESSION_JWT_LIFETIME`, `SESSION_JWT_ISSUER`: see `JwtConfig`.
+    /// * `SESSION_REFRESH_TTL`: issue refresh tokens valid that long.
+    pub async fn from_env(secrets: &SecretsClient) -> Result<SessionConfig, AppError> {
+        let mut config = SessionConfig::default();
+        if let Some(ttl) = env_var("SESSION_TTL") {
+            config.ttl = parse_duration(&ttl)?;
+        }
+        if let Some(delete_expired) = env_var("SESSION_DELETE_EXPIRED") {
+            config.delete_expired = parse_bool(&delete_expired)?;
+        }
+        if let Some(idle_timeout) = env_var("SESSION_IDLE_TIMEOUT") {
+            let touch_interval = match env_var("SESSION_TOUCH_INTERVAL") {
+                Some(touch_interval) => parse_duration(&touch_interval)?,
+                None => Duration::minutes(1),
+            };
+            config.sliding = Some(SlidingExpiration::new(
+                parse_duration(&idle_timeout)?,
+                touch_interval,
+            ));
+        }
+        if let Some(name) = env_var("SESSION_COOKIE_NAME") {
+            let mut cookie = SessionCookie::new(name);
+            if let Some(domain) = env_var("SESSION_COOKIE_DOMAIN") {
+                cookie = cookie.with_domain(domain);
+            }
+            if let Some(path) = env_var("SESSION_COOKIE_PATH") {
+                cookie = cookie.with_path(path);
+            }
+            if let Some(same_site) = env_var("SESSION_COOKIE_SAME_SITE") {
+                cookie = cookie.with_same_site(parse_same_site(&same_site)?);
+            }
+            if let Some(secure) = env_var("SESSION_COOKIE_SECURE") {
+                cookie = cookie.with_secure(parse_bool(&secure)?);
+            }
+            if let Some(http_only) = env_var("SESSION_COOKIE_HTTP_ONLY") {
+                cookie = cookie.with_http_only(parse_bool(&http_only)?);
+            }
+            cookie.validate()?;
+            config.cookie = Some(cookie);
+        }
+        config.hash_keys = hash_keys_from_env(secrets).await?;
+        if let Some(signing_keys) = env_var("SESSION_SIGNING_KEYS") {
+            let mut signed_tokens = SignedTokens::new(HashKeys::parse(&signing_keys)?);
+            if let Some(staleness) = env_var("SESSION_REVOCATION_STALENESS") {
+                signed_tokens = signed_tokens.with_max_staleness(parse_duration(&staleness)?);
+            }
+            config.signed_tokens = Some(signed_tokens);
+        }
+        if let Some(jwt_keys) = env_var("SESSION_JWT_KEYS") {
+            let mut jwt = JwtConfig::new(JwtKeys::load(&jwt_keys)?);
+            if let Some(lifetime) = env_var("SESSION_JWT_LIFETIME") {
+                jwt = jwt.with_lifetime(parse_duration(&lifetime)?);
+            }
+            if let Some(issuer) = env_var("SESSION_JWT_ISSUER") {
+                jwt = jwt.with_issuer(issuer);
+            }
+            config.jwt = Some(jwt);
+        }
+        if let Some(refresh_ttl) = env_var("SESSION_REFRESH_TTL") {
+            config.refresh_ttl = Some(parse_duration(&refresh_ttl)?);
+        }
+
+        Ok(config)
+    }
+
+    /// Set how long a session stays valid after its creation.
+    pub fn with_ttl(mut self, ttl: Duration) -> SessionConfig {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Delete expired sessions as soon as `get` comes across them instead of
+    /// waiting for the TTL sweeper.
+    pub fn with_delete_expired(mut self, delete_expired: bool) -> SessionConfig {
+        self.delete_expired = delete_expired;
+        self
+    }
+
+    /// Keep sessions alive while they are used, see `SlidingExpiration`.
+    pub fn with_sliding_expiration(mut self, sliding: SlidingExpiration) -> SessionConfig {
+        self.sliding = Some(sliding);
+        self
+    }
+
+    /// Accept the session id from a cookie when there is no bearer token.
+    pub fn with_cookie(mut self, cookie: SessionCookie) -> SessionConfig {
+        self.cookie = Some(cookie);
+        self
+    }
+
+    /// Set the secrets session ids are hashed with before being stored.
+    pub fn with_hash_keys(mut self, hash_keys: HashKeys) -> SessionConfig {
+        self.hash_keys = hash_keys;
+        self
+    }
+
+    /// Hand out signed tokens instead of opaque ones, see `SignedTokens`.
+    pub fn with_signed_tokens(mut self, signed_tokens: SignedTokens) -> SessionConfig {
+        self.signed_tokens = Some(signed_tokens);
+        self
+    }
+
+    /// Let sessions be exchanged for JWTs, see `JwtConfig`.
+    pub fn with_jwt(mut self, jwt: JwtConfig) -> SessionConfig {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Issue a refresh token along with every session, valid for
+    /// `refresh_ttl` after it was issued. See `SessionStore::refresh`.
+    pub fn with_refresh_ttl(mut self, refresh_ttl: Duration) -> SessionConfig {
+        self.refresh_ttl = Some(refresh_ttl);
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn sliding(&self) -> Option<SlidingExpiration> {
+        self.sliding
+    }
+
+    /// Lifetime given to a session when it is created.
+    pub fn initial_lifetime(&self) -> Duration {
+        match self.sliding {
+            Some(sliding) => sliding.idle_timeout.min(self.ttl),
+            None => self.ttl,
+        }
+    }
+
+    pub fn delete_expired(&self) -> bool {
+        self.delete_expired
+    }
+
+    pub fn cookie(&self) -> Option<&SessionCookie> {
+        self.cookie.as_ref()
+    }
+
+    pub fn hash_keys(&self) -> &HashKeys {
+        &self.hash_keys
+    }
+
+    pub fn signed_tokens(&self) -> Option<&SignedTokens> {
+        self.signed_tokens.as_ref()
+    }
+
+    pub fn jwt(&self) -> Option<&JwtConfig> {
+        self.jwt.as_ref()
+    }
+
+    pub fn refresh_ttl(&self) -> Option<Duration> {
+        self.refresh_ttl
+    }
+}
+
+async fn hash_keys_from_env(secrets: &SecretsClient) -> Result<HashKeys, AppError> {
+    let arn = match env_var("SESSION_HASH_KEYS_SECRET_ARN") {
+        Some(arn) => arn,
+        None => {
+            let hash_keys = env_var("SESSION_HASH_KEYS").ok_or_else(|| {
+                AppError::Validation(
+                    "SESSION_HASH_KEYS_SECRET_ARN or SESSION_HASH_KEYS must be set".to_owned(),
+                )
+            })?;
+            return HashKeys::parse(&hash_keys);
+        }
+    };
+    let secret = secrets.get_secret_value().secret_id(arn).send().await?;
+    let hash_keys = secret
+        .secret_string()
+        .ok_or_else(|| AppError::Validation("the hash keys secret must be a string".to_owned()))?;
+    parse_hash_keys_secret(hash_keys)
+}
+
+/// The hash keys of a secret value, a bare one being the key `k1`.
+fn parse_hash_keys_secret(value: &str) -> Result<HashKeys, AppError> {
+    if value.contains('=') {
+        HashKeys::parse(value)
+    } else {
+        HashKeys::parse(&format!("k1={}", value))
+    }
+}
+
+fn env_var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.trim().is_empty())
+}
+
+/// Longest duration `parse_duration` accepts. Expiries are computed by adding
+/// durations to the current time, which must not overflow.
+const MAX_DURATION_SECS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parse a positive duration such as `90`, `90s`, `15m`, `12h` or `7d`, of at
+/// most a hundred years.
+///
+/// A bare number is read as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, AppError> {
+    let value = value.trim();
+    let invalid = || AppError::Validation(format!("invalid duration: {:?}", value));
+
+    let (amount, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+    let amount = amount.parse::<i64>().map_err(|_| invalid())?;
+    if amount <= 0 {
+        return Err(invalid());
+    }
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    // `Duration::seconds` panics on overflow, so the bound is checked first.
+    match amount.checked_mul(unit_secs) {
+        Some(secs) if secs <= MAX_DURATION_SECS => Ok(Duration::seconds(secs)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, AppError> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(AppError::Validation(format!(
+            "invalid boolean: {:?}",
+            value
+        ))),
+    }
+}
+
+fn parse_same_site(value: &str) -> Result<SameSite, AppError> {
+    match value.trim().to_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => Err(AppError::Validation(format!(
+            "invalid SameSite: {:?}",
+            value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::minutes(15));
+        assert_eq!(parse_duration("12h").unwrap(), Duration::hours(12));
+        assert_eq!(parse_duration(" 7d ").unwrap(), Duration::days(7));
+    }
+
+    #[test]
+    fn hash_keys_secrets_may_be_bare() {
+        let keys = parse_hash_keys_secret("generated").unwrap();
+        assert_eq!(keys.current_id(), "k1");
+        assert!(keys.verify("token", &HashKeys::new("k1", "generated").hash("token")));
+
+        let keys = parse_hash_keys_secret("k2=new,k1=generated").unwrap();
+        assert_eq!(keys.current_id(), "k2");
+        assert!(keys.verify("token", &HashKeys::new("k1", "generated").hash("token")));
+    }
+
+    #[test]
+    fn parse_duration_rejects_garbage() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("7w").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflows() {
+        assert_eq!(parse_duration("36500d").unwrap(), Duration::days(36500));
+        assert!(parse_duration("36501d").is_err());
+        assert!(parse_duration("99999999999999d").is_err());
+        assert!(parse_duration("9223372036854775807s").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn default_ttl_is_a_week() {
+        assert_eq!(SessionConfig::default().ttl(), Duration::days(7));
+        assert_eq!(
+            SessionConfig::default().initial_lifetime(),
+            Duration::days(7)
+        );
+    }
+
+    #[test]
+    fn sliding_sessions_start_with_the_idle_window() {
+        let config = SessionConfig::new().with_sliding_expiration(SlidingExpiration::new(
+            Duration::minutes(30),
+            Duration::minutes(1),
+        ));
+        assert_eq!(config.initial_lifetime(), Duration::minutes(30));
+
+        let config = config.with_ttl(Duration::minutes(10));
+        assert_eq!(config.initial_lifetime(), Duration::minutes(10));
+    }
+
+    #[test]
+    fn session_cookie_headers() {
+        let cookie = SessionCookie::new("sid");
+        assert_eq!(
+            cookie.set("abc", Duration::hours(1)),
+            "sid=abc; Max-Age=3600; Path=/; SameSite=Lax; Secure; HttpOnly"
+        );
+
+        let cookie = cookie
+            .with_domain("example.com")
+            .with_path("/app")
+            .with_same_site(SameSite::Strict)
+            .with_secure(false)
+            .with_http_only(false);
+        assert_eq!(
+            cookie.clear(),
+            "sid=; Max-Age=0; Path=/app; Domain=example.com; SameSite=Strict"
+        );
+    }
+
+    #[test]
+    fn session_cookie_max_age_is_rounded_up() {
+        let cookie = SessionCookie::new("sid");
+        let max_age = |max_age| {
+            let header = cookie.set("abc", max_age);
+            header.split("; ").nth(1).unwrap().to_owned()
+        };
+
+        assert_eq!(max_age(Duration::milliseconds(3_599_990)), "Max-Age=3600");
+        assert_eq!(max_age(Duration::seconds(3600)), "Max-Age=3600");
+        assert_eq!(max_age(Duration::seconds(-5)), "Max-Age=0");
+    }
+
+    #[test]
+    fn same_site_none_cookies_must_be_secure() {
+        let cookie = SessionCookie::new("sid").with_same_site(SameSite::None);
+        assert!(cookie.validate().is_ok());
+        assert!(cookie.with_secure(false).validate().is_err());
+        assert!(SessionCookie::new("sid")
+            .with_secure(false)
+            .validate()
+            .is_ok());
+    }
+}
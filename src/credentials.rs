@@ -0,0 +1,353 @@
+//! # Credential verification for `api::create_session`.
+//!
+//! Users are checked against a stored password hash, either argon2 or bcrypt,
+//! recognised from the PHC/modular crypt prefix of the hash. Two sources ship
+//! with the crate: a static users file and a DynamoDB users table.
+//!
+//! The source is also trusted for the roles of users, which their sessions
+//! carry into JWTs, see `jwt`.
+
+use std::{collections::HashMap, env, fs, path::Path};
+
+use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{model::AttributeValue, Client};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{errors::AppError, ext::AttributeValuesExt};
+
+lazy_static! {
+    /// Checked in place of the hash of unknown users, so that turning them down
+    /// takes as long as a wrong password and does not tell which users exist.
+    /// It is an argon2 hash with the default parameters, the cost of stored
+    /// hashes should match.
+    static ref DUMMY_HASH: String = {
+        let salt = SaltString::new("ZHVtbXlzZXNzaW9uc2FsdA").expect("the salt is valid base64");
+        Argon2::default()
+            .hash_password(b"not a password", &salt)
+            .expect("hashing with the default parameters succeeds")
+            .to_string()
+    };
+}
+
+/// Checks a username and password pair.
+#[async_trait]
+pub trait CredentialVerifier: Send + Sync {
+    /// Return the user if `password` is the password of `username`, `None`
+    /// otherwise. Unknown users are not an error, they simply fail
+    /// verification, and no faster than a wrong password would.
+    async fn verify(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<VerifiedUser>, AppError>;
+}
+
+/// A user whose password checked out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifiedUser {
+    /// granted by the verifier, unlike session data clients cannot change them.
+    pub roles: Vec<String>,
+}
+
+/// Pick the verifier configured in the environment.
+///
+/// * `USERS_FILE`: path to a users file, see `StaticUsers::from_file`.
+/// * `USERS_TABLE_NAME`: name of a users table, see `DynamoDbUsers`.
+///
+/// The users file wins when both are set.
+pub fn from_env(ddb: &Client) -> Result<Box<dyn CredentialVerifier>, AppError> {
+    if let Ok(path) = env::var("USERS_FILE") {
+        return Ok(Box::new(StaticUsers::from_file(path)?));
+    }
+    match env::var("USERS_TABLE_NAME") {
+        Ok(table_name) => Ok(Box::new(DynamoDbUsers::new(ddb.clone(), table_name))),
+        Err(_) => Err(AppError::Validation(
+            "USERS_FILE or USERS_TABLE_NAME must be set".to_owned(),
+        )),
+    }
+}
+
+/// Users known ahead of time, mapped to their password hash and roles.
+#[derive(Debug, Clone, Default)]
+pub struct StaticUsers {
+    users: HashMap<String, StaticUser>,
+}
+
+/// A user of the users file, either a bare password hash or an object with
+/// roles.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum StaticUser {
+    Hash(String),
+    WithRoles {
+        password_hash: String,
+        #[serde(default)]
+        roles: Vec<String>,
+    },
+}
+
+impl StaticUser {
+    fn password_hash(&self) -> &str {
+        match self {
+            StaticUser::Hash(hash) => hash,
+            StaticUser::WithRoles { password_hash, .. } => password_hash,
+        }
+    }
+
+    fn roles(&self) -> Vec<String> {
+        match self {
+            StaticUser::Hash(_) => Vec::new(),
+            StaticUser::WithRoles { roles, .. } => roles.clone(),
+        }
+    }
+}
+
+impl StaticUsers {
+    /// Users without roles, mapped to their password hash.
+    pub fn new(users: HashMap<String, String>) -> StaticUsers {
+        StaticUsers::with_users(
+            users
+                .into_iter()
+                .map(|(username, hash)| (username, StaticUser::Hash(hash)))
+                .collect(),
+        )
+    }
+
+    fn with_users(users: HashMap<String, StaticUser>) -> StaticUsers {
+        // hashed now rather than while the first unknown user waits.
+        lazy_static::initialize(&DUMMY_HASH);
+        StaticUsers { users }
+    }
+
+    /// Grant `roles` to `username`, who must be known already.
+    pub fn with_roles(mut self, username: &str, roles: Vec<String>) -> StaticUsers {
+        if let Some(user) = self.users.get_mut(username) {
+            *user = StaticUser::WithRoles {
+                password_hash: user.password_hash().to_owned(),
+                roles,
+            };
+        }
+        self
+    }
+
+    /// Load users from a JSON object mapping usernames to password hashes, or
+    /// to objects with the hash and roles.
+    ///
+    /// ```json
+    /// {
+    ///   "alice": "$argon2id$v=19$m=19456,t=2,p=1$...",
+    ///   "bob": { "password_hash": "$2b$12$...", "roles": ["admin"] }
+    /// }
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<StaticUsers, AppError> {
+        let path = path.as_ref();
+        let contents = fs::read(path).map_err(|err| {
+            AppError::backend(format!(
+                "cannot read users file {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let users = serde_json::from_slice(&contents).map_err(|err| {
+            AppError::Validation(format!("invalid users file {}: {}", path.display(), err))
+        })?;
+
+        Ok(StaticUsers::with_users(users))
+    }
+}
+
+#[async_trait]
+impl CredentialVerifier for StaticUsers {
+    async fn verify(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<VerifiedUser>, AppError> {
+        let user = match self.users.get(username) {
+            Some(user) => user,
+            None => return verify_unknown_user(password).await,
+        };
+
+        let verified =
+            verify_password(password.to_owned(), user.password_hash().to_owned()).await?;
+        Ok(verified.then(|| VerifiedUser {
+            roles: user.roles(),
+        }))
+    }
+}
+
+/// Users stored in a DynamoDB table keyed by `PK = username`, with the hash in
+/// a `password_hash` string attribute and their roles, if any, in a `roles`
+/// string set.
+#[derive(Debug, Clone)]
+pub struct DynamoDbUsers {
+    table_name: String,
+    ddb: Client,
+}
+
+impl DynamoDbUsers {
+    pub fn new(ddb: Client, table_name: String) -> DynamoDbUsers {
+        lazy_static::initialize(&DUMMY_HASH);
+        DynamoDbUsers { table_name, ddb }
+    }
+}
+
+#[async_trait]
+impl CredentialVerifier for DynamoDbUsers {
+    #[instrument(skip(self, password))]
+    async fn verify(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<VerifiedUser>, AppError> {
+        let res = self
+            .ddb
+            .get_item()
+            .table_name(self.table_name.to_owned())
+            .key("PK", AttributeValue::S(username.to_owned()))
+            .projection_expression("password_hash, #roles")
+            .expression_attribute_names("#roles", "roles")
+            .send()
+            .await?;
+        let item = match res.item {
+            Some(item) => item,
+            None => return verify_unknown_user(password).await,
+        };
+
+        let hash = match item.get("password_hash") {
+            Some(AttributeValue::S(hash)) => hash.to_owned(),
+            Some(_) => return Err(AppError::backend("password_hash should be a string")),
+            None => return verify_unknown_user(password).await,
+        };
+
+        let verified = verify_password(password.to_owned(), hash).await?;
+        Ok(verified.then(|| VerifiedUser {
+            roles: item.get_ss("roles").unwrap_or_default(),
+        }))
+    }
+}
+
+/// Check `password` against an argon2 or bcrypt hash.
+///
+/// Hashing is deliberately slow, so it runs on the blocking thread pool
+/// instead of stalling the runtime.
+async fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    tokio::task::spawn_blocking(move || verify_hash(&password, &hash))
+        .await
+        .map_err(AppError::backend)?
+}
+
+/// Turn down a user that does not exist, as slowly as a wrong password.
+async fn verify_unknown_user(password: &str) -> Result<Option<VerifiedUser>, AppError> {
+    verify_password(password.to_owned(), DUMMY_HASH.clone()).await?;
+    Ok(None)
+}
+
+fn verify_hash(password: &str, hash: &str) -> Result<bool, AppError> {
+    if hash.starts_with("$argon2") {
+        let parsed = PasswordHash::new(hash)
+            .map_err(|err| AppError::backend(format!("invalid argon2 hash: {}", err)))?;
+        return Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok());
+    }
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).map_err(AppError::backend);
+    }
+
+    Err(AppError::backend("unsupported password hash"))
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{password_hash::SaltString, PasswordHasher};
+    use rand_core::OsRng;
+
+    use super::*;
+
+    fn argon2_hash(password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn static_users_verify_argon2_and_bcrypt() {
+        let users = StaticUsers::new(HashMap::from([
+            ("alice".to_owned(), argon2_hash("pingpong")),
+            ("bob".to_owned(), bcrypt::hash("moultipass", 4).unwrap()),
+        ]));
+
+        assert!(users.verify("alice", "pingpong").await.unwrap().is_some());
+        assert!(users.verify("alice", "moultipass").await.unwrap().is_none());
+        assert!(users.verify("bob", "moultipass").await.unwrap().is_some());
+        assert!(users.verify("bob", "pingpong").await.unwrap().is_none());
+        assert!(users.verify("carol", "pingpong").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn verified_users_carry_their_roles() {
+        let users = StaticUsers::new(HashMap::from([
+            ("alice".to_owned(), bcrypt::hash("pingpong", 4).unwrap()),
+            ("bob".to_owned(), bcrypt::hash("pingpong", 4).unwrap()),
+        ]))
+        .with_roles("alice", vec!["admin".to_owned()])
+        .with_roles("carol", vec!["admin".to_owned()]);
+
+        let alice = users.verify("alice", "pingpong").await.unwrap().unwrap();
+        assert_eq!(alice.roles, ["admin"]);
+        let bob = users.verify("bob", "pingpong").await.unwrap().unwrap();
+        assert!(bob.roles.is_empty());
+        assert!(users.verify("alice", "moultipass").await.unwrap().is_none());
+        assert!(users.verify("carol", "pingpong").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unknown_users_cost_a_password_check() {
+        let users = StaticUsers::new(HashMap::new());
+
+        assert!(verify_hash("not a password", &DUMMY_HASH).unwrap());
+        // even with the password of the dummy hash.
+        assert!(users
+            .verify("carol", "not a password")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unknown_hash_formats_are_errors() {
+        assert!(verify_hash("pingpong", "pingpong").is_err());
+        assert!(verify_hash("pingpong", "$argon2id$v=19$m=19456,t=2,p=1$!!$!!").is_err());
+    }
+
+    #[test]
+    fn users_file_is_a_json_object() {
+        let path = env::temp_dir().join(format!("users-{}.json", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"{
+                "alice": "$2b$04$abcdefghijklmnopqrstuu",
+                "bob": { "password_hash": "$2b$04$abcdefghijklmnopqrstuu", "roles": ["admin"] }
+            }"#,
+        )
+        .unwrap();
+
+        let users = StaticUsers::from_file(&path).unwrap();
+        assert!(users.users["alice"].roles().is_empty());
+        assert_eq!(users.users["bob"].roles(), ["admin"]);
+        assert_eq!(
+            users.users["bob"].password_hash(),
+            "$2b$04$abcdefghijklmnopqrstuu"
+        );
+
+        fs::write(&path, r#"["alice"]"#).unwrap();
+        assert!(StaticUsers::from_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,42 +1,171 @@
 use std::error;
 use std::fmt;
 
-use serde::Deserialize;
 use aws_sdk_dynamodb::types::SdkError;
-use serde::Serialize;
+use aws_smithy_types::retry::{ErrorKind, ProvideErrorKind};
+use http::StatusCode;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AppError {
-    details: String,
+pub type BoxError = Box<dyn error::Error + Send + Sync + 'static>;
+
+/// Errors surfaced by the store, its backends and the handlers.
+///
+/// Each variant maps to one HTTP status and one stable error code, see
+/// `status_code` and `code`.
+#[derive(Debug)]
+pub enum AppError {
+    /// The requested resource does not exist.
+    NotFound(String),
+    /// The session exists but is past its expiry.
+    Expired,
+    /// No session matches the token presented.
+    InvalidSession,
+    /// The caller could not be authenticated, or may not do this.
+    Unauthorized(String),
+    /// The request or a configuration value is malformed.
+    Validation(String),
+    /// The backend is rejecting requests because of their rate.
+    Throttled { source: BoxError },
+    /// A conditional write lost against a concurrent one.
+    Conflict(String),
+    /// Any other failure of the backend, or of the service itself.
+    Backend { source: BoxError },
 }
 
 impl AppError {
-    pub fn new(msg: &str) -> AppError {
-        AppError {
-            details: msg.to_string(),
+    /// Wrap an unexpected failure, keeping it as the error source.
+    pub fn backend<E: Into<BoxError>>(source: E) -> AppError {
+        AppError::Backend {
+            source: source.into(),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Expired | AppError::InvalidSession | AppError::Unauthorized(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Throttled { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Backend { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Message for clients. Throttling and backend failures get a fixed one,
+    /// as their source may describe the internals of the service.
+    pub fn public_message(&self) -> String {
+        match self {
+            AppError::Throttled { .. } => "too many requests, retry later".to_owned(),
+            AppError::Backend { .. } => "internal error".to_owned(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Machine-readable code for clients, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::Expired => "session_expired",
+            AppError::InvalidSession => "invalid_session",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Validation(_) => "validation_error",
+            AppError::Throttled { .. } => "throttled",
+            AppError::Conflict(_) => "conflict",
+            AppError::Backend { .. } => "backend_error",
         }
     }
 }
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
+        match self {
+            AppError::NotFound(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Validation(msg)
+            | AppError::Conflict(msg) => write!(f, "{}", msg),
+            AppError::Expired => write!(f, "session has expired"),
+            AppError::InvalidSession => write!(f, "session does not exist"),
+            AppError::Throttled { source } => write!(f, "request throttled: {}", source),
+            AppError::Backend { source } => write!(f, "backend error: {}", source),
+        }
     }
 }
 
-impl std::error::Error for AppError {
-    fn description(&self) -> &str {
-        &self.details
+impl error::Error for AppError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AppError::Throttled { source } | AppError::Backend { source } => Some(source.as_ref()),
+            _ => None,
+        }
     }
 }
 
 impl<E> From<SdkError<E>> for AppError
 where
-    E: error::Error,
+    E: error::Error + ProvideErrorKind + Send + Sync + 'static,
 {
     fn from(value: SdkError<E>) -> AppError {
-        AppError {
-            details: format!("{}", value),
+        if let SdkError::ServiceError { err, .. } = &value {
+            let throttled = err.retryable_error_kind() == Some(ErrorKind::ThrottlingError)
+                || matches!(
+                    err.code(),
+                    Some("ProvisionedThroughputExceededException")
+                        | Some("RequestLimitExceeded")
+                        | Some("ThrottlingException")
+                );
+            if throttled {
+                return AppError::Throttled {
+                    source: Box::new(value),
+                };
+            }
+            if err.code() == Some("ConditionalCheckFailedException") {
+                return AppError::Conflict(err.to_string());
+            }
         }
+
+        AppError::backend(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn variants_map_to_status_and_code() {
+        let cases = [
+            (AppError::NotFound("gone".to_owned()), 404, "not_found"),
+            (AppError::Expired, 401, "session_expired"),
+            (AppError::InvalidSession, 401, "invalid_session"),
+            (
+                AppError::Unauthorized("nope".to_owned()),
+                401,
+                "unauthorized",
+            ),
+            (
+                AppError::Validation("bad".to_owned()),
+                400,
+                "validation_error",
+            ),
+            (AppError::Conflict("raced".to_owned()), 409, "conflict"),
+            (AppError::backend("boom"), 500, "backend_error"),
+        ];
+        for (err, status, code) in cases {
+            assert_eq!(err.status_code().as_u16(), status);
+            assert_eq!(err.code(), code);
+        }
+    }
+
+    #[test]
+    fn backend_errors_keep_their_source() {
+        let io = std::io::Error::other("disk on fire");
+        let err = AppError::backend(io);
+
+        assert_eq!(err.to_string(), "backend error: disk on fire");
+        assert_eq!(err.source().unwrap().to_string(), "disk on fire");
+        assert_eq!(err.public_message(), "internal error");
     }
 }
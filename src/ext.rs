@@ -11,8 +11,11 @@ use std::collections::HashMap;
 /// to extract those values.
 pub trait AttributeValuesExt {
     fn get_s(&self, key: &str) -> Option<String>;
+    /// only the tests read numbers.
+    #[cfg(test)]
     fn get_n(&self, key: &str) -> Option<f64>;
     fn get_dt(&self, key: &str) -> Option<DateTime<Utc>>;
+    fn get_ss(&self, key: &str) -> Option<Vec<String>>;
 }
 
 impl AttributeValuesExt for HashMap<String, AttributeValue> {
@@ -44,6 +47,7 @@ impl AttributeValuesExt for HashMap<String, AttributeValue> {
     ///   }
     /// }
     /// ```
+    #[cfg(test)]
     fn get_n(&self, key: &str) -> Option<f64> {
         self.get(key)?.as_n().ok()?.parse::<f64>().ok()
     }
@@ -68,6 +72,22 @@ impl AttributeValuesExt for HashMap<String, AttributeValue> {
             .parse::<DateTime<Utc>>()
             .ok()
     }
+
+    /// Return a string set from a key
+    ///
+    /// E.g. if you run `get_ss("roles")` on a DynamoDB item structured like this,
+    /// you will retrieve the value `["admin", "billing"]`.
+    ///
+    /// ```json
+    /// {
+    ///  "roles": {
+    ///   "SS": ["admin", "billing"]
+    ///   }
+    /// }
+    /// ```
+    fn get_ss(&self, key: &str) -> Option<Vec<String>> {
+        Some(self.get(key)?.as_ss().ok()?.to_owned())
+    }
 }
 
 #[cfg(test)]
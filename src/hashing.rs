@@ -0,0 +1,319 @@
+//! # Keyed hashing of session tokens.
+//!
+//! The store never persists the token handed to the client. Sessions are
+//! stored under `<key id>.<HMAC-SHA256 of the token>`, so reading the table,
+//! or a backup of it, is not enough to impersonate anyone.
+//!
+//! Keys rotate by adding a new current key and keeping the previous ones until
+//! the sessions they hashed have expired, that is one session TTL. Tokens name
+//! their key, see `token`, so previous keys cost no extra reads.
+//!
+//! Sessions created before ids were hashed, stored under the raw UUID handed to
+//! the client, cannot be found any more: deploying hashed ids logs everyone
+//! out once. Their items expire through the table TTL.
+//!
+//! The same construction signs stateless tokens, see `token::sign`, and the
+//! same secrets seal the page tokens of session listings, see `HashKeys::seal`.
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use sha2::Sha256;
+
+use crate::errors::AppError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What the sealing key is derived from. Tokens never contain a NUL byte, so
+/// no token hashes to the sealing key.
+const SEALING_KEY_LABEL: &[u8] = b"ddbs-page-token-seal-v1\0";
+
+/// The secrets session tokens are hashed with, the first one being current.
+#[derive(Clone)]
+pub struct HashKeys {
+    keys: Vec<HashKey>,
+}
+
+#[derive(Clone)]
+struct HashKey {
+    id: String,
+    secret: Vec<u8>,
+}
+
+impl HashKeys {
+    /// Hash new sessions with `secret`, tagged with `id`.
+    pub fn new(id: impl Into<String>, secret: impl Into<Vec<u8>>) -> HashKeys {
+        HashKeys {
+            keys: vec![HashKey {
+                id: id.into(),
+                secret: secret.into(),
+            }],
+        }
+    }
+
+    /// Keep recognising sessions hashed with a retired key.
+    pub fn with_previous(mut self, id: impl Into<String>, secret: impl Into<Vec<u8>>) -> HashKeys {
+        self.keys.push(HashKey {
+            id: id.into(),
+            secret: secret.into(),
+        });
+        self
+    }
+
+    /// Parse a comma separated list of `id=secret` pairs, current key first.
+    ///
+    /// ```
+    /// use ddb_session_store::hashing::HashKeys;
+    ///
+    /// let keys = HashKeys::parse("2024-06=new-secret,2024-01=old-secret").unwrap();
+    /// assert!(keys.hash("token").starts_with("2024-06."));
+    /// ```
+    pub fn parse(value: &str) -> Result<HashKeys, AppError> {
+        let mut keys = Vec::new();
+        for pair in value.split(',').map(str::trim) {
+            let (id, secret) = pair
+                .split_once('=')
+                .filter(|(id, secret)| is_key_id(id) && !secret.is_empty())
+                .ok_or_else(|| {
+                    AppError::Validation(format!("invalid hash key {:?}", id_of(pair)))
+                })?;
+            keys.push(HashKey {
+                id: id.to_owned(),
+                secret: secret.as_bytes().to_vec(),
+            });
+        }
+
+        Ok(HashKeys { keys })
+    }
+
+    /// The id of the current key, which new tokens name.
+    pub fn current_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    /// The stored id of `token`, under the current key.
+    pub fn hash(&self, token: &str) -> String {
+        self.keys[0].hash(token)
+    }
+
+    /// The stored id of `token` under the key `id`, `None` if that key is
+    /// unknown, retired for good for instance.
+    pub fn hash_with(&self, id: &str, token: &str) -> Option<String> {
+        self.keys
+            .iter()
+            .find(|key| key.id == id)
+            .map(|key| key.hash(token))
+    }
+
+    /// Whether `hash` is the hash of `message` under one of the keys. The
+    /// digests are compared in constant time.
+    pub fn verify(&self, message: &str, hash: &str) -> bool {
+        let (id, digest) = match hash.split_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let digest = match base64::decode_config(digest, base64::URL_SAFE_NO_PAD) {
+            Ok(digest) => digest,
+            Err(_) => return false,
+        };
+
+        self.keys
+            .iter()
+            .filter(|key| key.id == id)
+            .any(|key| key.mac(message).verify_slice(&digest).is_ok())
+    }
+
+    /// Encrypt `message` with the current key, bound to `context`: the result
+    /// only opens with the same context. Used for values handed to clients
+    /// that they should neither read nor forge.
+    pub fn seal(&self, message: &str, context: &str) -> Result<String, AppError> {
+        let key = &self.keys[0];
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(AppError::backend)?;
+
+        let mut sealed = message.as_bytes().to_vec();
+        key.sealing_key()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(context.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| AppError::backend("cannot seal message"))?;
+        let mut token = nonce.to_vec();
+        token.extend(sealed);
+
+        Ok(format!(
+            "{}.{}",
+            key.id,
+            base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// The message `seal` encrypted with one of the keys and `context`.
+    pub fn open(&self, sealed: &str, context: &str) -> Option<String> {
+        let (id, sealed) = sealed.split_once('.')?;
+        let key = self.keys.iter().find(|key| key.id == id)?;
+        let mut sealed = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at_mut(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let message = key
+            .sealing_key()
+            .open_in_place(nonce, Aad::from(context.as_bytes()), ciphertext)
+            .ok()?;
+        String::from_utf8(message.to_vec()).ok()
+    }
+}
+
+impl HashKey {
+    fn hash(&self, token: &str) -> String {
+        let digest = self.mac(token).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            self.id,
+            base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn mac(&self, message: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    /// A ChaCha20-Poly1305 key derived from the secret, distinct from the
+    /// hashes of tokens.
+    fn sealing_key(&self) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &self.sealing_secret())
+                .expect("HMAC-SHA256 yields 32 bytes"),
+        )
+    }
+
+    fn sealing_secret(&self) -> Vec<u8> {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(SEALING_KEY_LABEL);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// A key with an empty secret, for tests and local runs. Anyone can compute
+/// these hashes, so they hide nothing: deployments must configure secrets,
+/// which `SessionConfig::from_env` insists on.
+impl Default for HashKeys {
+    fn default() -> Self {
+        HashKeys::new("0", Vec::new())
+    }
+}
+
+/// Secrets are left out.
+impl fmt::Debug for HashKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.keys.iter().map(|key| &key.id))
+            .finish()
+    }
+}
+
+pub(crate) fn is_key_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// the key id of an `id=secret` pair, to report errors without the secret.
+fn id_of(pair: &str) -> &str {
+    pair.split_once('=').map(|(id, _)| id).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_keyed_and_tagged() {
+        let keys = HashKeys::new("k1", "secret");
+
+        let hash = keys.hash("token");
+        assert!(hash.starts_with("k1."));
+        assert!(!hash.contains("token"));
+        assert_eq!(hash, keys.hash("token"));
+        assert_ne!(hash, keys.hash("other"));
+        assert_ne!(hash, HashKeys::new("k1", "other secret").hash("token"));
+    }
+
+    #[test]
+    fn tokens_hash_with_the_key_they_name() {
+        let old = HashKeys::new("k1", "old");
+        let keys = HashKeys::new("k2", "new").with_previous("k1", "old");
+
+        assert_eq!(keys.current_id(), "k2");
+        assert_eq!(keys.hash_with("k2", "token"), Some(keys.hash("token")));
+        assert_eq!(keys.hash_with("k1", "token"), Some(old.hash("token")));
+        assert_eq!(keys.hash_with("k0", "token"), None);
+    }
+
+    #[test]
+    fn verify_accepts_hashes_of_any_key() {
+        let keys = HashKeys::new("k2", "new").with_previous("k1", "old");
+
+        assert!(keys.verify("token", &keys.hash("token")));
+        assert!(keys.verify("token", &HashKeys::new("k1", "old").hash("token")));
+        assert!(!keys.verify("other", &keys.hash("token")));
+        assert!(!keys.verify("token", &HashKeys::new("k1", "new").hash("token")));
+        assert!(!keys.verify("token", &HashKeys::new("k3", "new").hash("token")));
+        assert!(!keys.verify("token", "k2"));
+    }
+
+    #[test]
+    fn sealed_messages_only_open_in_their_context() {
+        let old = HashKeys::new("k1", "old");
+        let keys = HashKeys::new("k2", "new").with_previous("k1", "old");
+
+        let sealed = keys.seal("k1.abc", "alice").unwrap();
+        assert!(sealed.starts_with("k2."));
+        assert!(!sealed.contains("abc"));
+        assert_ne!(sealed, keys.seal("k1.abc", "alice").unwrap());
+        assert_eq!(keys.open(&sealed, "alice").as_deref(), Some("k1.abc"));
+        assert_eq!(keys.open(&sealed, "bob"), None);
+        assert_eq!(old.open(&sealed, "alice"), None);
+
+        let sealed = old.seal("k1.abc", "alice").unwrap();
+        assert_eq!(keys.open(&sealed, "alice").as_deref(), Some("k1.abc"));
+        assert_eq!(keys.open("k2.", "alice"), None);
+        assert_eq!(keys.open("k2.!!", "alice"), None);
+    }
+
+    #[test]
+    fn sealing_keys_are_not_token_hashes() {
+        let key = HashKey {
+            id: "k1".to_owned(),
+            secret: b"secret".to_vec(),
+        };
+        let secret = key.sealing_secret();
+        for token in ["seal", "ddbs-page-token-seal-v1"] {
+            assert_ne!(secret, key.mac(token).finalize().into_bytes().to_vec());
+        }
+    }
+
+    #[test]
+    fn parse_rejects_malformed_keys_without_leaking_them() {
+        let keys = HashKeys::parse(" k2=new , k1=old==").unwrap();
+        assert_eq!(format!("{:?}", keys), r#"["k2", "k1"]"#);
+
+        for value in ["", "k1", "k1=", "=secret", "k.1=secret", "k1=a,"] {
+            assert!(HashKeys::parse(value).is_err(), "{:?} should fail", value);
+        }
+        for value in ["k 1=hunter2", "hunter2"] {
+            let err = HashKeys::parse(value).unwrap_err();
+            assert!(!err.to_string().contains("hunter2"));
+        }
+    }
+}
@@ -1,6 +1,12 @@
 pub mod utils;
 pub mod store;
+pub mod token;
+pub mod backend;
+pub mod config;
+pub mod credentials;
 pub mod errors;
+pub mod hashing;
+pub mod jwt;
 mod ext;
 pub mod alb;
 pub mod api;
\ No newline at end of file
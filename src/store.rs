@@ -1,173 +1,1304 @@
-use aws_sdk_dynamodb::{
-    model::{AttributeValue, DeleteRequest, WriteRequest},
-    Client,
+use aws_sdk_dynamodb::Client;
+use chrono::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Mutex};
+use tracing::{instrument, warn};
+
+use crate::{
+    backend::{DynamoDbBackend, SessionBackend},
+    config::{SessionConfig, SignedTokens},
+    errors::AppError,
+    token::{self, SessionClaims},
 };
-use chrono::{prelude::*, Duration};
-use std::collections::HashMap;
-use tracing::{info, instrument};
-use uuid::Uuid;
 
-use crate::{errors::AppError, ext::AttributeValuesExt};
+/// Sessions of a backend, with the rules of `SessionConfig` applied.
+///
+/// Clients hold a session *token*, which only `create` ever returns. The
+/// backend stores the session under a keyed hash of that token, its `id`, see
+/// `hashing`. `get` takes a token, while the methods acting on a session loaded
+/// earlier take its `id`.
+///
+/// With `SignedTokens` configured, the token is a signed copy of the `id`
+/// instead, see `token::sign`.
+pub struct SessionStore<B> {
+    config: SessionConfig,
+    backend: B,
+    /// sessions of signed tokens that passed a revocation check, with when.
+    /// These are snapshots: writes through this store drop them, writes
+    /// through other instances show once they go stale.
+    verified: Mutex<HashMap<String, (Session, DateTime<Utc>)>>,
+}
 
-pub struct SessionStore<'a> {
-    table_name: String,
-    expiration: i64,
-    ddb: &'a Client,
+/// Outcome of a session lookup.
+#[derive(Debug)]
+pub enum SessionLookup {
+    Found(Box<Session>),
+    /// no session is stored under that id.
+    NotFound,
+    /// the session exists but is past its `expires_at`. DynamoDB TTL deletion
+    /// can lag by up to 48 hours, so such items must never authenticate.
+    Expired,
+}
+
+impl SessionStore<DynamoDbBackend> {
+    pub fn new(ddb: &Client, table_name: String) -> SessionStore<DynamoDbBackend> {
+        SessionStore::with_backend(DynamoDbBackend::new(ddb.clone(), table_name))
+    }
 }
 
-impl SessionStore<'_> {
-    pub fn new(ddb: &Client, table_name: String) -> SessionStore {
+impl<B: SessionBackend> SessionStore<B> {
+    pub fn with_backend(backend: B) -> SessionStore<B> {
         SessionStore {
-            table_name,
-            expiration: 7 * 86400000,
-            ddb,
+            config: SessionConfig::default(),
+            backend,
+            verified: Mutex::new(HashMap::new()),
         }
     }
 
-    pub async fn get(&self, id: String) -> Result<Session, AppError> {
-        let res = self
-            .ddb
-            .get_item()
-            .table_name(self.table_name.to_owned())
-            .key(
-                "PK",
-                aws_sdk_dynamodb::model::AttributeValue::S(id.to_owned()),
-            )
-            .send()
-            .await?;
+    pub fn with_config(mut self, config: SessionConfig) -> SessionStore<B> {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Look up the session of `token`. Malformed tokens are turned down
+    /// without reaching the backend.
+    pub async fn get(&self, token: String) -> Result<SessionLookup, AppError> {
+        if let Some(signed) = self.config.signed_tokens() {
+            if token.starts_with(token::SIGNED_PREFIX) {
+                return self.get_signed(signed, &token).await;
+            }
+        }
+        if !token::is_well_formed(&token) {
+            return Ok(SessionLookup::NotFound);
+        }
+
+        let session = match self.stored_id(&token) {
+            Some(id) => self.backend.get(&id).await?,
+            None => None,
+        };
+        match session {
+            Some(session) => self.check_expiry(session).await,
+            None => Ok(SessionLookup::NotFound),
+        }
+    }
 
-        match res.item {
-            Some(item) => Ok(item.try_into()?),
-            None => Err(AppError::new("Session does not exist.")),
+    /// The id a well-formed token is stored under, hashed with the key it
+    /// names. `None` once that key has been retired.
+    fn stored_id(&self, token: &str) -> Option<String> {
+        let key_id = token::key_id(token)?;
+        self.config.hash_keys().hash_with(key_id, token)
+    }
+
+    /// Look up the session of a signed token. The lookup is the revocation
+    /// check: revoked sessions are deleted, by id or through `GSI1` for all the
+    /// sessions of a user.
+    async fn get_signed(
+        &self,
+        signed: &SignedTokens,
+        token: &str,
+    ) -> Result<SessionLookup, AppError> {
+        let claims = match token::verify(signed.keys(), token) {
+            Some(claims) => claims,
+            None => return Ok(SessionLookup::NotFound),
+        };
+        let now = Utc::now();
+        if claims.expires_at <= now.timestamp() {
+            return Ok(SessionLookup::Expired);
+        }
+
+        if let Some(session) = self.recently_verified(&claims.session_id, signed, now) {
+            return Ok(SessionLookup::Found(Box::new(session)));
         }
+
+        let session = match self.backend.get(&claims.session_id).await? {
+            Some(session) => session,
+            None => return Ok(SessionLookup::NotFound),
+        };
+        let lookup = self.check_expiry(session).await?;
+        if let SessionLookup::Found(session) = &lookup {
+            self.remember(session, signed, now);
+        }
+
+        Ok(lookup)
+    }
+
+    /// The session stored under `id` if it passed a revocation check less than
+    /// `max_staleness` ago and has not expired since.
+    fn recently_verified(
+        &self,
+        id: &str,
+        signed: &SignedTokens,
+        now: DateTime<Utc>,
+    ) -> Option<Session> {
+        let verified = self.verified.lock().unwrap();
+        let (session, checked_at) = verified.get(id)?;
+        if now - *checked_at >= signed.max_staleness() || session.is_expired_at(now) {
+            return None;
+        }
+        Some(session.clone())
     }
 
-    pub async fn create(&self, username: String) -> Result<String, AppError> {
-        self.create_at(username, Utc::now()).await
+    fn remember(&self, session: &Session, signed: &SignedTokens, now: DateTime<Utc>) {
+        let max_staleness = signed.max_staleness();
+        if max_staleness <= chrono::Duration::zero() {
+            return;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, (_, checked_at)| now - *checked_at < max_staleness);
+        verified.insert(session.id.clone(), (session.clone(), now));
+    }
+
+    /// Drop the sessions matching `changed` from the revocation check cache,
+    /// revoked or written to, so that this instance at least does not wait for
+    /// them to go stale.
+    fn forget(&self, changed: impl Fn(&Session) -> bool) {
+        self.verified
+            .lock()
+            .unwrap()
+            .retain(|_, (session, _)| !changed(session));
+    }
+
+    async fn check_expiry(&self, session: Session) -> Result<SessionLookup, AppError> {
+        if !session.is_expired() {
+            return Ok(SessionLookup::Found(Box::new(session)));
+        }
+
+        if self.config.delete_expired() {
+            if let Err(err) = self.backend.delete(&session.id).await {
+                warn!("failed to delete expired session: {}", err);
+            }
+        }
+
+        Ok(SessionLookup::Expired)
+    }
+
+    /// Start a session of `username` granted `roles`, as told by the
+    /// credential verifier.
+    pub async fn create(
+        &self,
+        username: String,
+        roles: Vec<String>,
+        client: ClientMetadata,
+    ) -> Result<SessionTokens, AppError> {
+        self.create_at(username, roles, client, Utc::now()).await
     }
 
     pub async fn create_at(
         &self,
         username: String,
+        roles: Vec<String>,
+        client: ClientMetadata,
         created_at: DateTime<Utc>,
-    ) -> Result<String, AppError> {
-        let id = Uuid::new_v4();
-        let session = &Session {
-            id: id.to_string(),
-            username: username.clone(),
+    ) -> Result<SessionTokens, AppError> {
+        let token = token::generate(self.config.hash_keys().current_id())?;
+        let mut session = Session::new(
+            self.config.hash_keys().hash(&token),
+            username,
             created_at,
-            expires_at: created_at + Duration::seconds(self.expiration),
+            created_at + self.config.initial_lifetime(),
+        );
+        session.roles = roles;
+        session.client = client;
+
+        let refresh_token = match self.config.refresh_ttl() {
+            Some(refresh_ttl) => {
+                // the family is named after the session that started it.
+                session.family = Some(session.id.clone());
+                let (refresh_token, refresh) = self.refresh_record(&session, refresh_ttl)?;
+                self.backend.create_with_refresh(&session, &refresh).await?;
+                Some(refresh_token)
+            }
+            None => {
+                self.backend.create(&session).await?;
+                None
+            }
         };
 
-        self.ddb
-            .put_item()
-            .table_name(self.table_name.to_owned())
-            .set_item(Some(session.into()))
-            .send()
-            .await?;
+        Ok(SessionTokens {
+            expires_at: session.expires_at,
+            token: self.client_token(token, session)?,
+            refresh_token,
+        })
+    }
 
-        Ok(id.to_string())
+    /// Trade `refresh_token` for a new session and refresh token, see
+    /// `SessionConfig::with_refresh_ttl`.
+    ///
+    /// The renewed session is revoked and the refresh token spent atomically
+    /// with the creation of the new ones. The new session carries the data of
+    /// the old one over, if it is still stored. A refresh token presented
+    /// twice has leaked: every session of its family is revoked.
+    pub async fn refresh(
+        &self,
+        refresh_token: String,
+        client: ClientMetadata,
+    ) -> Result<SessionTokens, AppError> {
+        self.refresh_at(refresh_token, client, Utc::now()).await
     }
 
-    #[instrument(skip(self))]
-    pub async fn delete_user_sessions(&self, username: String) -> Result<(), AppError> {
-        let res = self
-            .ddb
-            .query()
-            .table_name(self.table_name.clone())
-            .index_name("GSI1")
-            .key_condition_expression("#username = :username".to_owned())
-            .expression_attribute_names("#username".to_owned(), "GSI1PK".to_owned())
-            .expression_attribute_values(
-                ":username".to_owned(),
-                AttributeValue::S(username.to_owned()),
-            )
-            .send()
+    pub async fn refresh_at(
+        &self,
+        refresh_token: String,
+        client: ClientMetadata,
+        now: DateTime<Utc>,
+    ) -> Result<SessionTokens, AppError> {
+        let refresh_ttl = self
+            .config
+            .refresh_ttl()
+            .ok_or_else(|| AppError::NotFound("Refresh tokens are not enabled.".to_owned()))?;
+        let invalid = || AppError::Unauthorized("Invalid refresh token.".to_owned());
+        if !token::is_well_formed_refresh(&refresh_token) {
+            return Err(invalid());
+        }
+
+        let old = match self.stored_id(&refresh_token) {
+            Some(id) => self.backend.get_refresh(&id).await?,
+            None => None,
+        };
+        let old = old.ok_or_else(invalid)?;
+        if old.rotated {
+            warn!("refresh token reused, revoking its family");
+            self.revoke_family(&old).await?;
+            return Err(invalid());
+        }
+        if old.expires_at <= now {
+            return Err(AppError::Expired);
+        }
+
+        let token = token::generate(self.config.hash_keys().current_id())?;
+        let mut session = Session::new(
+            self.config.hash_keys().hash(&token),
+            old.username.clone(),
+            now,
+            now + self.config.initial_lifetime(),
+        );
+        session.roles = old.roles.clone();
+        session.client = client;
+        session.family = Some(old.family.clone());
+        if let Some(renewed) = self.backend.get(&old.session_id).await? {
+            session.data = renewed.data;
+        }
+        let (refresh_token, refresh) = self.refresh_record(&session, refresh_ttl)?;
+
+        match self.backend.rotate(&old, &session, &refresh).await {
+            Ok(()) => {}
+            // another request spent it first: one of them holds a leaked copy.
+            Err(AppError::Conflict(_)) => {
+                warn!("refresh token rotated concurrently, revoking its family");
+                self.revoke_family(&old).await?;
+                return Err(invalid());
+            }
+            Err(err) => return Err(err),
+        }
+        self.forget(|cached| cached.id == old.session_id);
+
+        Ok(SessionTokens {
+            expires_at: session.expires_at,
+            token: self.client_token(token, session)?,
+            refresh_token: Some(refresh_token),
+        })
+    }
+
+    /// Draw the refresh token of `session`, valid for `refresh_ttl`.
+    fn refresh_record(
+        &self,
+        session: &Session,
+        refresh_ttl: chrono::Duration,
+    ) -> Result<(String, RefreshRecord), AppError> {
+        let refresh_token = token::generate_refresh(self.config.hash_keys().current_id())?;
+        let refresh = RefreshRecord {
+            id: self.config.hash_keys().hash(&refresh_token),
+            session_id: session.id.clone(),
+            family: session.family.clone().unwrap_or_else(|| session.id.clone()),
+            username: session.username.clone(),
+            roles: session.roles.clone(),
+            expires_at: session.created_at + refresh_ttl,
+            rotated: false,
+        };
+        Ok((refresh_token, refresh))
+    }
+
+    /// The token handing `session` to the client, `token` unless sessions are
+    /// handed out as signed tokens.
+    fn client_token(&self, token: String, session: Session) -> Result<String, AppError> {
+        match self.config.signed_tokens() {
+            // the opaque token is only drawn to derive the id, and dropped.
+            Some(signed) => token::sign(
+                signed.keys(),
+                &SessionClaims {
+                    expires_at: (session.created_at + self.config.ttl()).timestamp(),
+                    session_id: session.id,
+                    username: session.username,
+                },
+            ),
+            None => Ok(token),
+        }
+    }
+
+    async fn revoke_family(&self, refresh: &RefreshRecord) -> Result<usize, AppError> {
+        let revoked = self
+            .backend
+            .delete_family(&refresh.username, &refresh.family)
             .await?;
+        self.forget(|session| session.family.as_ref() == Some(&refresh.family));
+        Ok(revoked)
+    }
 
-        info!("{} sessions found for {}", res.count(), username);
+    /// Return the data of the active session of `token`, `None` if it is
+    /// missing or expired.
+    pub async fn get_data(&self, token: String) -> Result<Option<SessionData>, AppError> {
+        match self.get(token).await? {
+            SessionLookup::Found(session) => Ok(Some(session.data)),
+            _ => Ok(None),
+        }
+    }
 
-        let deletes: Vec<WriteRequest> = res
-            .items
-            .unwrap_or_default()
-            .into_iter()
-            .map(|item| {
-                let hk = item.get("PK").unwrap().to_owned();
-                WriteRequest::builder()
-                    .delete_request(DeleteRequest::builder().key("PK".to_owned(), hk).build())
-                    .build()
-            })
-            .collect();
+    /// Replace the data of a session. Returns whether the session exists.
+    pub async fn set_data(&self, id: String, data: SessionData) -> Result<bool, AppError> {
+        let exists = self.backend.set_data(&id, &data).await?;
+        self.forget(|session| session.id == id);
+        Ok(exists)
+    }
+
+    /// Merge `changes` into the data of a session, `null` values removing their
+    /// key. Returns the resulting data, `None` if the session does not exist.
+    pub async fn update_data(
+        &self,
+        id: String,
+        changes: SessionData,
+    ) -> Result<Option<SessionData>, AppError> {
+        if changes.is_empty() {
+            return match self.backend.get(&id).await? {
+                Some(session) if !session.is_expired() => Ok(Some(session.data)),
+                _ => Ok(None),
+            };
+        }
+        let data = self.backend.update_data(&id, &changes).await?;
+        self.forget(|session| session.id == id);
+        Ok(data)
+    }
+
+    /// Push the expiry of a sliding session forward, see `SlidingExpiration`.
+    ///
+    /// This is a no-op unless sliding expiration is configured and the new
+    /// expiry beats the current one by at least the touch interval. Returns
+    /// whether the session was updated, in which case `session` is updated too.
+    pub async fn touch(&self, session: &mut Session) -> Result<bool, AppError> {
+        self.touch_at(session, Utc::now()).await
+    }
 
-        let batch_request = self.ddb.batch_write_item();
-        batch_request
-            .request_items(self.table_name.clone(), deletes)
-            .send()
+    pub async fn touch_at(
+        &self,
+        session: &mut Session,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AppError> {
+        let sliding = match self.config.sliding() {
+            Some(sliding) => sliding,
+            None => return Ok(false),
+        };
+
+        let expires_at = (now + sliding.idle_timeout()).min(session.created_at + self.config.ttl());
+        if expires_at - session.expires_at < sliding.touch_interval() {
+            return Ok(false);
+        }
+
+        let touched = self.backend.touch(&session.id, expires_at).await?;
+        if touched {
+            session.expires_at = expires_at;
+            self.forget(|cached| cached.id == session.id);
+        }
+
+        Ok(touched)
+    }
+
+    /// Revoke one session of `username`. Sessions of other users are left
+    /// alone, so a caller can only revoke their own. Returns whether a session
+    /// was deleted.
+    ///
+    /// With refresh tokens, the refresh tokens of the session go too.
+    #[instrument(skip(self, id))]
+    pub async fn delete(&self, id: String, username: String) -> Result<bool, AppError> {
+        let family = match self.config.refresh_ttl() {
+            Some(_) => self
+                .backend
+                .get(&id)
+                .await?
+                .filter(|session| session.username == username)
+                .and_then(|session| session.family),
+            None => None,
+        };
+
+        let deleted = self.backend.delete_if_owned(&id, &username).await?;
+        self.forget(|session| session.id == id);
+        if let Some(family) = family.filter(|_| deleted) {
+            self.backend.delete_family(&username, &family).await?;
+        }
+        Ok(deleted)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_user_sessions(&self, username: String) -> Result<usize, AppError> {
+        let deleted = self.backend.delete_user_sessions(&username).await?;
+        self.forget(|session| session.username == username);
+        Ok(deleted)
+    }
+
+    /// List the active sessions of `username`, `limit` at a time.
+    ///
+    /// `page_token` is the `next_token` of the previous page. Pages may hold
+    /// fewer than `limit` sessions as expired ones are left out.
+    ///
+    /// Page tokens are sealed with the hash keys, see `HashKeys::seal`: the
+    /// stored id they resume after is neither readable nor forgeable, and a
+    /// page token of one user is no good for another.
+    #[instrument(skip(self, page_token))]
+    pub async fn list_user_sessions(
+        &self,
+        username: String,
+        limit: usize,
+        page_token: Option<String>,
+    ) -> Result<SessionPage, AppError> {
+        let keys = self.config.hash_keys();
+        let start_after = page_token
+            .map(|token| {
+                keys.open(&token, &username)
+                    .ok_or_else(|| AppError::Validation("invalid page token".to_owned()))
+            })
+            .transpose()?;
+        let (sessions, last_key) = self
+            .backend
+            .list_user_sessions(&username, limit, start_after.as_deref())
             .await?;
 
-        Ok(())
+        let now = Utc::now();
+        Ok(SessionPage {
+            sessions: sessions
+                .into_iter()
+                .filter(|session| !session.is_expired_at(now))
+                .collect(),
+            next_token: last_key
+                .map(|last_key| keys.seal(&last_key, &username))
+                .transpose()?,
+        })
     }
 }
 
+/// One page of `SessionStore::list_user_sessions`.
+#[derive(Debug)]
+pub struct SessionPage {
+    pub sessions: Vec<Session>,
+    /// token of the next page, `None` on the last one.
+    pub next_token: Option<String>,
+}
+
+/// What `SessionStore::create` and `SessionStore::refresh` hand to the client.
+#[derive(Debug, Clone)]
+pub struct SessionTokens {
+    pub token: String,
+    /// when the session of `token` expires, unless it is touched.
+    pub expires_at: DateTime<Utc>,
+    /// `None` unless refresh tokens are enabled.
+    pub refresh_token: Option<String>,
+}
+
+/// A refresh token as stored, under the keyed hash of the token like sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefreshRecord {
+    pub id: String,
+    /// the session it renews.
+    pub session_id: String,
+    /// shared by the sessions renewed from one another, down to the first one.
+    pub family: String,
+    pub username: String,
+    /// the roles of the session, carried over to the one it is traded for.
+    pub roles: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+    /// whether it was traded already, presenting it again is a reuse.
+    pub rotated: bool,
+}
+
+/// Application data attached to a session, stored as a DynamoDB `M` attribute.
+pub type SessionData = HashMap<String, Value>;
+
+/// Describes the client a session was created from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientMetadata {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Session {
     pub id: String,
-    created_at: DateTime<Utc>,
-    expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
     pub username: String,
+    /// granted at login by the credential verifier. Unlike `data`, clients
+    /// cannot write them.
+    pub roles: Vec<String>,
+    pub client: ClientMetadata,
+    pub data: SessionData,
+    /// the refresh token family of the session, `None` without refresh tokens.
+    pub family: Option<String>,
 }
 
 impl Session {
-    pub fn is_expired(self) -> bool {
-        self.expires_at <= Utc::now()
+    pub fn new(
+        id: String,
+        username: String,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Session {
+        Session {
+            id,
+            created_at,
+            expires_at,
+            username,
+            roles: Vec::new(),
+            client: ClientMetadata::default(),
+            data: SessionData::new(),
+            family: None,
+        }
+    }
+
+    /// Read a data entry as `T`, `None` if it is missing or of another shape.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        serde_json::from_value(self.data.get(key)?.clone()).ok()
+    }
+
+    /// Set a data entry locally, use `SessionStore::set_data` to persist it.
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: T) -> Result<(), AppError> {
+        let value = serde_json::to_value(value).map_err(AppError::backend)?;
+        self.data.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Utc::now())
+    }
+
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
     }
 }
 
-impl From<&Session> for HashMap<String, AttributeValue> {
-    fn from(value: &Session) -> Self {
-        let mut retval = HashMap::new();
-        // Indexing attributes
-        retval.insert("PK".to_owned(), AttributeValue::S(value.id.to_owned()));
-        retval.insert(
-            "GSI1PK".to_owned(),
-            AttributeValue::S(value.username.to_owned()),
-        );
-        retval.insert(
-            "TTL".to_owned(),
-            AttributeValue::N(value.expires_at.timestamp().to_string()),
-        );
-        // Item attributes
-        retval.insert("id".to_owned(), AttributeValue::S(value.id.to_owned()));
-        retval.insert(
-            "created_at".to_owned(),
-            AttributeValue::S(value.created_at.to_rfc3339()),
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{backend::InMemoryBackend, config::SlidingExpiration, hashing::HashKeys};
+
+    /// the id the session of `token` is stored under.
+    fn id_of<B: SessionBackend>(store: &SessionStore<B>, token: &str) -> String {
+        store.config().hash_keys().hash(token)
+    }
+
+    async fn store_with_expired_session(
+        delete_expired: bool,
+    ) -> (SessionStore<InMemoryBackend>, String) {
+        let token = token::generate("0").unwrap();
+        let store = SessionStore::with_backend(InMemoryBackend::new())
+            .with_config(SessionConfig::new().with_delete_expired(delete_expired));
+        let created_at = Utc::now() - Duration::days(2);
+        store
+            .backend()
+            .create(&Session::new(
+                id_of(&store, &token),
+                "alice".to_owned(),
+                created_at,
+                created_at + Duration::days(1),
+            ))
+            .await
+            .unwrap();
+        (store, token)
+    }
+
+    #[tokio::test]
+    async fn get_reports_expired_sessions() {
+        let (store, token) = store_with_expired_session(false).await;
+
+        let lookup = store.get(token).await.unwrap();
+        assert!(matches!(lookup, SessionLookup::Expired));
+        assert_eq!(store.backend().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_deletes_expired_sessions_when_asked() {
+        let (store, token) = store_with_expired_session(true).await;
+
+        let lookup = store.get(token).await.unwrap();
+        assert!(matches!(lookup, SessionLookup::Expired));
+        assert!(store.backend().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_reports_missing_sessions() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+
+        let lookup = store.get("missing".to_owned()).await.unwrap();
+        assert!(matches!(lookup, SessionLookup::NotFound));
+    }
+
+    #[tokio::test]
+    async fn create_uses_default_ttl() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+        let created_at = Utc::now();
+
+        let id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+
+        let session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.expires_at, created_at + Duration::days(7));
+    }
+
+    #[tokio::test]
+    async fn create_uses_configured_ttl() {
+        let store = SessionStore::with_backend(InMemoryBackend::new())
+            .with_config(SessionConfig::new().with_ttl(Duration::minutes(30)));
+        let created_at = Utc::now();
+
+        let id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+
+        let session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.created_at, created_at);
+        assert_eq!(session.expires_at, created_at + Duration::minutes(30));
+    }
+
+    fn sliding_store() -> SessionStore<InMemoryBackend> {
+        SessionStore::with_backend(InMemoryBackend::new()).with_config(
+            SessionConfig::new()
+                .with_ttl(Duration::hours(8))
+                .with_sliding_expiration(SlidingExpiration::new(
+                    Duration::minutes(30),
+                    Duration::minutes(1),
+                )),
+        )
+    }
+
+    #[tokio::test]
+    async fn touch_slides_expiry() {
+        let store = sliding_store();
+        let created_at = Utc::now();
+        let id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+        let mut session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.expires_at, created_at + Duration::minutes(30));
+
+        let now = created_at + Duration::minutes(10);
+        assert!(store.touch_at(&mut session, now).await.unwrap());
+        assert_eq!(session.expires_at, now + Duration::minutes(30));
+
+        let stored = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.expires_at, now + Duration::minutes(30));
+    }
+
+    #[tokio::test]
+    async fn touch_is_throttled() {
+        let store = sliding_store();
+        let created_at = Utc::now();
+        let id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+        let mut session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let now = created_at + Duration::seconds(30);
+        assert!(!store.touch_at(&mut session, now).await.unwrap());
+        assert_eq!(session.expires_at, created_at + Duration::minutes(30));
+    }
+
+    #[tokio::test]
+    async fn touch_never_exceeds_ttl() {
+        let store = sliding_store();
+        let created_at = Utc::now();
+        let id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+        let mut session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let now = created_at + Duration::hours(7) + Duration::minutes(50);
+        assert!(store.touch_at(&mut session, now).await.unwrap());
+        assert_eq!(session.expires_at, created_at + Duration::hours(8));
+
+        let now = now + Duration::minutes(5);
+        assert!(!store.touch_at(&mut session, now).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn touch_is_disabled_by_default() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+        let created_at = Utc::now();
+        let id = store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap()
+            .token;
+        let mut session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let now = created_at + Duration::days(1);
+        assert!(!store.touch_at(&mut session, now).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn data_can_be_replaced_and_merged() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+        let id = store
+            .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap()
+            .token;
+        assert_eq!(
+            store.get_data(id.clone()).await.unwrap(),
+            Some(SessionData::new())
         );
-        retval.insert(
-            "expires_at".to_owned(),
-            AttributeValue::S(value.expires_at.to_rfc3339()),
+
+        let mut session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        session.insert("roles", vec!["admin"]).unwrap();
+        session.insert("tenant", "acme").unwrap();
+        assert!(store
+            .set_data(id_of(&store, &id), session.data)
+            .await
+            .unwrap());
+
+        let changes = SessionData::from([
+            ("tenant".to_owned(), Value::Null),
+            ("csrf".to_owned(), Value::from("token")),
+        ]);
+        let data = store
+            .update_data(id_of(&store, &id), changes)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.len(), 2);
+
+        let session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            session.get::<Vec<String>>("roles"),
+            Some(vec!["admin".to_owned()])
         );
-        retval.insert(
-            "username".to_owned(),
-            AttributeValue::S(value.username.to_owned()),
+        assert_eq!(session.get::<String>("csrf"), Some("token".to_owned()));
+        assert_eq!(session.get::<String>("tenant"), None);
+    }
+
+    #[tokio::test]
+    async fn data_writes_require_a_session() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+
+        assert!(!store
+            .set_data("missing".to_owned(), SessionData::new())
+            .await
+            .unwrap());
+        let changes = SessionData::from([("csrf".to_owned(), Value::from("token"))]);
+        assert!(store
+            .update_data("missing".to_owned(), changes)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn list_user_sessions_pages_through_active_sessions() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+        for _ in 0..5 {
+            store
+                .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+                .await
+                .unwrap();
+        }
+        store
+            .create("bob".to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap();
+        let created_at = Utc::now() - Duration::days(8);
+        store
+            .create_at(
+                "alice".to_owned(),
+                Vec::new(),
+                ClientMetadata::default(),
+                created_at,
+            )
+            .await
+            .unwrap();
+
+        let mut seen = Vec::new();
+        let mut page_token = None;
+        loop {
+            let page = store
+                .list_user_sessions("alice".to_owned(), 2, page_token)
+                .await
+                .unwrap();
+            assert!(page.sessions.len() <= 2);
+            seen.extend(page.sessions.into_iter().map(|session| session.id));
+            page_token = match page.next_token {
+                Some(token) => Some(token),
+                None => break,
+            };
+        }
+
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn list_user_sessions_rejects_garbage_tokens() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+
+        let res = store
+            .list_user_sessions("alice".to_owned(), 10, Some("!!".to_owned()))
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn page_tokens_are_opaque_and_bound_to_their_user() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+        for _ in 0..3 {
+            store
+                .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+                .await
+                .unwrap();
+        }
+
+        let page = store
+            .list_user_sessions("alice".to_owned(), 1, None)
+            .await
+            .unwrap();
+        let next_token = page.next_token.unwrap();
+        assert!(!next_token.contains(&page.sessions[0].id));
+        let encoded = base64::encode_config(&page.sessions[0].id, base64::URL_SAFE_NO_PAD);
+        assert!(!next_token.contains(&encoded));
+
+        assert!(store
+            .list_user_sessions("bob".to_owned(), 1, Some(next_token.clone()))
+            .await
+            .is_err());
+        let next = store
+            .list_user_sessions("alice".to_owned(), 1, Some(next_token))
+            .await
+            .unwrap();
+        assert_ne!(next.sessions[0].id, page.sessions[0].id);
+
+        let forged = base64::encode_config(&page.sessions[0].id, base64::URL_SAFE_NO_PAD);
+        assert!(store
+            .list_user_sessions("alice".to_owned(), 1, Some(forged))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn create_records_client_metadata() {
+        let store = SessionStore::with_backend(InMemoryBackend::new());
+        let client = ClientMetadata {
+            user_agent: Some("curl/7.79.1".to_owned()),
+            ip_address: Some("72.12.164.125".to_owned()),
+        };
+
+        let id = store
+            .create("alice".to_owned(), Vec::new(), client.clone())
+            .await
+            .unwrap()
+            .token;
+
+        let session = store
+            .backend()
+            .get(&id_of(&store, &id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.client, client);
+    }
+
+    #[tokio::test]
+    async fn tokens_are_only_stored_hashed() {
+        let store = SessionStore::with_backend(InMemoryBackend::new())
+            .with_config(SessionConfig::new().with_hash_keys(HashKeys::new("k1", "secret")));
+
+        let token = store
+            .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap()
+            .token;
+
+        assert!(store.backend().get(&token).await.unwrap().is_none());
+        let session = store
+            .backend()
+            .get(&id_of(&store, &token))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(token.starts_with("ddbs_k1."));
+        assert!(session.id.starts_with("k1."));
+        assert!(!session.id.contains(&token));
+    }
+
+    #[tokio::test]
+    async fn get_finds_sessions_hashed_with_previous_keys() {
+        let old_keys = HashKeys::new("k1", "old");
+        let token = token::generate("k1").unwrap();
+        let stored_id = old_keys.hash(&token);
+        let store_with = |keys: HashKeys| {
+            let stored_id = stored_id.clone();
+            async move {
+                let store = SessionStore::with_backend(InMemoryBackend::new())
+                    .with_config(SessionConfig::new().with_hash_keys(keys));
+                let now = Utc::now();
+                store
+                    .backend()
+                    .create(&Session::new(
+                        stored_id,
+                        "alice".to_owned(),
+                        now,
+                        now + Duration::days(1),
+                    ))
+                    .await
+                    .unwrap();
+                store
+            }
+        };
+
+        let rotated = store_with(HashKeys::new("k2", "new").with_previous("k1", "old")).await;
+        match rotated.get(token.clone()).await.unwrap() {
+            SessionLookup::Found(session) => assert_eq!(session.id, stored_id),
+            lookup => panic!("expected the session, got {:?}", lookup),
+        }
+
+        let retired = store_with(HashKeys::new("k2", "new")).await;
+        assert!(matches!(
+            retired.get(token).await.unwrap(),
+            SessionLookup::NotFound
+        ));
+    }
+
+    fn signed_store(max_staleness: Duration) -> SessionStore<InMemoryBackend> {
+        let signed =
+            SignedTokens::new(HashKeys::new("s1", "signing")).with_max_staleness(max_staleness);
+        SessionStore::with_backend(InMemoryBackend::new())
+            .with_config(SessionConfig::new().with_signed_tokens(signed))
+    }
+
+    async fn found(store: &SessionStore<InMemoryBackend>, token: &str) -> Option<Session> {
+        match store.get(token.to_owned()).await.unwrap() {
+            SessionLookup::Found(session) => Some(*session),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_tokens_are_checked_for_revocation() {
+        let store = signed_store(Duration::zero());
+        let token = store
+            .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap()
+            .token;
+        assert!(token.starts_with(token::SIGNED_PREFIX));
+
+        let session = found(&store, &token).await.unwrap();
+        assert_eq!(session.username, "alice");
+        assert_eq!(
+            token::verify(&HashKeys::new("s1", "signing"), &token)
+                .unwrap()
+                .session_id,
+            session.id
         );
 
-        retval
+        let forged = token::sign(
+            &HashKeys::new("s1", "guess"),
+            &SessionClaims {
+                session_id: session.id.clone(),
+                username: "alice".to_owned(),
+                expires_at: session.expires_at.timestamp(),
+            },
+        )
+        .unwrap();
+        assert!(found(&store, &forged).await.is_none());
+
+        store
+            .delete_user_sessions("alice".to_owned())
+            .await
+            .unwrap();
+        assert!(found(&store, &token).await.is_none());
     }
-}
 
-impl TryFrom<HashMap<String, AttributeValue>> for Session {
-    type Error = AppError;
-    fn try_from(value: HashMap<String, AttributeValue>) -> Result<Self, Self::Error> {
-        Ok(Session {
-            id: value.get_s("id").ok_or(AppError::new("missing id"))?,
-            created_at: value
-                .get_dt("created_at")
-                .ok_or(AppError::new("missing created_at date"))?,
-            expires_at: value
-                .get_dt("expires_at")
-                .ok_or(AppError::new("missing expires_at date"))?,
-            username: value
-                .get_s("username")
-                .ok_or(AppError::new("missing username"))?,
-        })
+    #[tokio::test]
+    async fn revocation_checks_are_skipped_while_fresh() {
+        let store = signed_store(Duration::minutes(1));
+        let token = store
+            .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap()
+            .token;
+        let session = found(&store, &token).await.unwrap();
+
+        // revoked behind the back of this instance, as by another Lambda.
+        store.backend().delete(&session.id).await.unwrap();
+        assert!(found(&store, &token).await.is_some());
+
+        store.delete(session.id, "alice".to_owned()).await.unwrap();
+        assert!(found(&store, &token).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fresh_sessions_are_snapshots_until_written_through_the_store() {
+        let store = signed_store(Duration::minutes(1));
+        let token = store
+            .create("alice".to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap()
+            .token;
+        let session = found(&store, &token).await.unwrap();
+        let data: SessionData = [("theme".to_owned(), Value::from("dark"))]
+            .into_iter()
+            .collect();
+
+        // written by another instance: served stale until the check is due.
+        store.backend().set_data(&session.id, &data).await.unwrap();
+        assert!(found(&store, &token).await.unwrap().data.is_empty());
+
+        store
+            .set_data(session.id.clone(), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(found(&store, &token).await.unwrap().data, data);
+
+        let changes: SessionData = [("theme".to_owned(), Value::Null)].into_iter().collect();
+        store.update_data(session.id, changes).await.unwrap();
+        assert!(found(&store, &token).await.unwrap().data.is_empty());
+    }
+
+    fn refreshing_store() -> SessionStore<InMemoryBackend> {
+        SessionStore::with_backend(InMemoryBackend::new())
+            .with_config(SessionConfig::new().with_refresh_ttl(Duration::days(30)))
+    }
+
+    async fn login(store: &SessionStore<InMemoryBackend>, username: &str) -> SessionTokens {
+        store
+            .create(username.to_owned(), Vec::new(), ClientMetadata::default())
+            .await
+            .unwrap()
+    }
+
+    async fn refresh(
+        store: &SessionStore<InMemoryBackend>,
+        tokens: &SessionTokens,
+    ) -> Result<SessionTokens, AppError> {
+        let refresh_token = tokens.refresh_token.clone().unwrap();
+        store
+            .refresh(refresh_token, ClientMetadata::default())
+            .await
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_the_session() {
+        let store = refreshing_store();
+        let first = store
+            .create(
+                "alice".to_owned(),
+                vec!["admin".to_owned()],
+                ClientMetadata::default(),
+            )
+            .await
+            .unwrap();
+        assert!(token::is_well_formed_refresh(
+            first.refresh_token.as_deref().unwrap()
+        ));
+        let changes = SessionData::from([("theme".to_owned(), Value::from("dark"))]);
+        store
+            .update_data(id_of(&store, &first.token), changes)
+            .await
+            .unwrap();
+
+        let second = refresh(&store, &first).await.unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert!(matches!(
+            store.get(first.token.clone()).await.unwrap(),
+            SessionLookup::NotFound
+        ));
+        match store.get(second.token.clone()).await.unwrap() {
+            SessionLookup::Found(session) => {
+                assert_eq!(session.username, "alice");
+                assert_eq!(session.roles, ["admin"]);
+                assert_eq!(session.get::<String>("theme").unwrap(), "dark");
+            }
+            lookup => panic!("expected the new session, got {:?}", lookup),
+        }
+        assert_eq!(store.backend().len(), 1);
+
+        let expired = store
+            .refresh_at(
+                second.refresh_token.unwrap(),
+                ClientMetadata::default(),
+                Utc::now() + Duration::days(31),
+            )
+            .await;
+        assert!(matches!(expired, Err(AppError::Expired)));
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_tokens_revoke_the_family() {
+        let store = refreshing_store();
+        let first = login(&store, "alice").await;
+        let other = login(&store, "alice").await;
+        let second = refresh(&store, &first).await.unwrap();
+
+        assert!(matches!(
+            refresh(&store, &first).await,
+            Err(AppError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            store.get(second.token.clone()).await.unwrap(),
+            SessionLookup::NotFound
+        ));
+        assert!(refresh(&store, &second).await.is_err());
+
+        // other logins are families of their own.
+        assert!(refresh(&store, &other).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_not_sessions() {
+        let store = refreshing_store();
+        let tokens = login(&store, "alice").await;
+        let refresh_token = tokens.refresh_token.clone().unwrap();
+        let refresh_id = id_of(&store, &refresh_token);
+
+        assert!(matches!(
+            store.get(refresh_token).await.unwrap(),
+            SessionLookup::NotFound
+        ));
+        assert!(!store.delete(refresh_id, "alice".to_owned()).await.unwrap());
+        assert!(refresh(&store, &tokens).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn revoked_sessions_cannot_be_refreshed() {
+        let store = refreshing_store();
+        let logged_out = login(&store, "alice").await;
+        let revoked = login(&store, "alice").await;
+
+        store
+            .delete(id_of(&store, &logged_out.token), "alice".to_owned())
+            .await
+            .unwrap();
+        assert!(refresh(&store, &logged_out).await.is_err());
+
+        store
+            .delete_user_sessions("alice".to_owned())
+            .await
+            .unwrap();
+        assert!(refresh(&store, &revoked).await.is_err());
+    }
+
+    #[test]
+    fn session_expires_at_its_deadline() {
+        let now = Utc::now();
+        let session = Session::new(
+            "id".to_owned(),
+            "alice".to_owned(),
+            now - Duration::hours(1),
+            now,
+        );
+
+        assert!(!session.is_expired_at(now - Duration::seconds(1)));
+        assert!(session.is_expired_at(now));
     }
 }
@@ -0,0 +1,217 @@
+//! # Session tokens handed to clients.
+//!
+//! A token reads `ddbs_<key id>.<secret><checksum>`:
+//!
+//! * `ddbs_` tells session tokens apart in logs and lets secret scanners
+//!   recognise them. Refresh tokens use `ddbr_` instead.
+//! * the key id names the `HashKeys` key the session is stored under, so a
+//!   lookup takes a single read whatever the number of keys.
+//! * the secret is 256 bits from the OS CSPRNG, base64url-encoded.
+//! * the checksum is the start of the SHA-256 of the rest of the token. It
+//!   catches typos and junk without a trip to the backend, it is no signature.
+//!
+//! With `SignedTokens` configured, clients get `ddbst_<claims>.<signature>`
+//! instead, the claims being base64url JSON and the signature a `HashKeys`
+//! hash of everything before it.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::AppError,
+    hashing::{self, HashKeys},
+};
+
+pub const PREFIX: &str = "ddbs_";
+pub const REFRESH_PREFIX: &str = "ddbr_";
+pub const SIGNED_PREFIX: &str = "ddbst_";
+
+const SECRET_BYTES: usize = 32;
+const CHECKSUM_BYTES: usize = 4;
+/// base64url lengths, without padding, of the secret and of the checksum.
+const SECRET_LEN: usize = 43;
+const CHECKSUM_LEN: usize = 6;
+
+/// Draw a new session token, to be stored under the key `key_id`.
+pub fn generate(key_id: &str) -> Result<String, AppError> {
+    generate_with(PREFIX, key_id)
+}
+
+/// Draw a new refresh token, to be stored under the key `key_id`.
+pub fn generate_refresh(key_id: &str) -> Result<String, AppError> {
+    generate_with(REFRESH_PREFIX, key_id)
+}
+
+/// Whether `token` could have come from `generate`. Tokens failing this check
+/// cannot match any session.
+pub fn is_well_formed(token: &str) -> bool {
+    has_form(token, PREFIX)
+}
+
+/// Whether `token` could have come from `generate_refresh`.
+pub fn is_well_formed_refresh(token: &str) -> bool {
+    has_form(token, REFRESH_PREFIX)
+}
+
+/// The id of the key a well-formed token is stored under.
+pub fn key_id(token: &str) -> Option<&str> {
+    let (_, rest) = token.split_once('_')?;
+    rest.split_once('.').map(|(key_id, _)| key_id)
+}
+
+fn generate_with(prefix: &str, key_id: &str) -> Result<String, AppError> {
+    let mut secret = [0u8; SECRET_BYTES];
+    getrandom::getrandom(&mut secret).map_err(AppError::backend)?;
+
+    let mut token = format!("{}{}.{}", prefix, key_id, encode(&secret));
+    token.push_str(&checksum(&token));
+    Ok(token)
+}
+
+fn has_form(token: &str, prefix: &str) -> bool {
+    let (key_id, rest) = match token
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.split_once('.'))
+    {
+        Some(parts) => parts,
+        None => return false,
+    };
+    if !hashing::is_key_id(key_id) || rest.len() != SECRET_LEN + CHECKSUM_LEN || !rest.is_ascii() {
+        return false;
+    }
+    let (unchecked, expected) = token.split_at(token.len() - CHECKSUM_LEN);
+    let secret = &rest[..SECRET_LEN];
+
+    base64::decode_config(secret, base64::URL_SAFE_NO_PAD)
+        .map(|secret| secret.len() == SECRET_BYTES)
+        .unwrap_or(false)
+        && checksum(unchecked) == expected
+}
+
+/// What a signed token vouches for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionClaims {
+    /// the id the session is stored under.
+    #[serde(rename = "sid")]
+    pub session_id: String,
+    #[serde(rename = "sub")]
+    pub username: String,
+    /// epoch seconds after which the token is no longer valid.
+    #[serde(rename = "exp")]
+    pub expires_at: i64,
+}
+
+/// Sign `claims` with the current key of `keys`.
+pub fn sign(keys: &HashKeys, claims: &SessionClaims) -> Result<String, AppError> {
+    let claims = serde_json::to_vec(claims).map_err(AppError::backend)?;
+    let unsigned = format!("{}{}", SIGNED_PREFIX, encode(&claims));
+    let signature = keys.hash(&unsigned);
+    Ok(format!("{}.{}", unsigned, signature))
+}
+
+/// The claims of `token` if one of `keys` signed it. Their expiry is left to
+/// the caller.
+pub fn verify(keys: &HashKeys, token: &str) -> Option<SessionClaims> {
+    if !token.starts_with(SIGNED_PREFIX) {
+        return None;
+    }
+    // base64url has no dots, the signature starts after the first one.
+    let (unsigned, signature) = token.split_once('.')?;
+    if !keys.verify(unsigned, signature) {
+        return None;
+    }
+
+    let claims =
+        base64::decode_config(&unsigned[SIGNED_PREFIX.len()..], base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&claims).ok()
+}
+
+fn checksum(unchecked: &str) -> String {
+    let digest = Sha256::digest(unchecked.as_bytes());
+    encode(&digest[..CHECKSUM_BYTES])
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_tokens_are_well_formed() {
+        let token = generate("k1").unwrap();
+
+        assert!(token.starts_with("ddbs_k1."));
+        assert_eq!(token.len(), 57);
+        assert!(is_well_formed(&token));
+        assert_eq!(key_id(&token), Some("k1"));
+        assert_ne!(token, generate("k1").unwrap());
+
+        let refresh = generate_refresh("2024-06").unwrap();
+        assert!(refresh.starts_with("ddbr_2024-06."));
+        assert!(is_well_formed_refresh(&refresh));
+        assert_eq!(key_id(&refresh), Some("2024-06"));
+        assert!(!is_well_formed(&refresh));
+        assert!(!is_well_formed_refresh(&token));
+    }
+
+    #[test]
+    fn junk_is_rejected() {
+        let token = generate("k1").unwrap();
+        let mut typo = token.clone().into_bytes();
+        typo[10] = if typo[10] == b'A' { b'B' } else { b'A' };
+        let typo = String::from_utf8(typo).unwrap();
+
+        let junk = [
+            String::new(),
+            "ddbs_".to_owned(),
+            "ddbs_k1.".to_owned(),
+            "2b1e4c5e-7d8f-4a6b-9c0d-1e2f3a4b5c6d".to_owned(),
+            token[..token.len() - 1].to_owned(),
+            format!("{}A", token),
+            token.replacen("ddbs_", "ddbx_", 1),
+            token.replacen("k1.", "k2.", 1),
+            token.replacen("k1.", ".", 1),
+            token.replacen("k1.", "k 1.", 1),
+            typo,
+            format!("ddbs_k1.{}a", "é".repeat(24)),
+        ];
+        for junk in junk {
+            assert!(!is_well_formed(&junk), "{:?} should be rejected", junk);
+        }
+    }
+
+    #[test]
+    fn signed_tokens_carry_their_claims() {
+        let keys = HashKeys::new("k2", "new").with_previous("k1", "old");
+        let claims = SessionClaims {
+            session_id: "0.abc".to_owned(),
+            username: "alice".to_owned(),
+            expires_at: 1_700_000_000,
+        };
+
+        let token = sign(&keys, &claims).unwrap();
+        assert!(token.starts_with("ddbst_"));
+        assert!(!is_well_formed(&token));
+        assert_eq!(verify(&keys, &token), Some(claims.clone()));
+
+        let old = sign(&HashKeys::new("k1", "old"), &claims).unwrap();
+        assert_eq!(verify(&keys, &old), Some(claims.clone()));
+
+        let forged = sign(&HashKeys::new("k2", "guess"), &claims).unwrap();
+        assert_eq!(verify(&keys, &forged), None);
+
+        let (_, signature) = token.split_once('.').unwrap();
+        let mut bob = claims;
+        bob.username = "bob".to_owned();
+        let swapped = format!(
+            "ddbst_{}.{}",
+            encode(&serde_json::to_vec(&bob).unwrap()),
+            signature
+        );
+        assert_eq!(verify(&keys, &swapped), None);
+        assert_eq!(verify(&keys, &generate("k2").unwrap()), None);
+    }
+}
@@ -2,8 +2,10 @@ use std::time;
 
 use aws_config::{meta::region::RegionProviderChain, SdkConfig};
 use aws_smithy_types::{timeout, tristate::TriState};
-use lambda_http::{http::StatusCode, Response};
-use serde_json::json;
+use lambda_http::{http::StatusCode, Context, Request, Response};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{error, warn};
 
 use crate::errors::AppError;
 
@@ -44,16 +46,159 @@ pub fn response(status_code: StatusCode, body: String) -> Response<String> {
         .unwrap()
 }
 
+/// JSON body of every error response.
+///
+/// ```json
+/// {
+///   "code": "validation_error",
+///   "message": "limit must be between 1 and 100",
+///   "requestId": "c6af9ac6-7b61-11e6-9a41-93e8deadbeef",
+///   "fieldErrors": [{ "field": "limit", "message": "must be between 1 and 100" }]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub field_errors: Vec<FieldError>,
+}
+
+/// Points an error at one field of the request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(code: &str, message: &str) -> ErrorBody {
+        ErrorBody {
+            code: code.to_owned(),
+            message: message.to_owned(),
+            request_id: None,
+            details: None,
+            field_errors: Vec::new(),
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: Option<String>) -> ErrorBody {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn with_details(mut self, details: Value) -> ErrorBody {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn with_field_error(mut self, field: &str, message: &str) -> ErrorBody {
+        self.field_errors.push(FieldError {
+            field: field.to_owned(),
+            message: message.to_owned(),
+        });
+        self
+    }
+}
+
+/// Throttling and backend failures are logged here, as clients only get a
+/// fixed message for them.
+impl From<&AppError> for ErrorBody {
+    fn from(err: &AppError) -> ErrorBody {
+        match err {
+            AppError::Throttled { .. } => warn!("{}", err),
+            AppError::Backend { .. } => error!("{}", err),
+            _ => {}
+        }
+        ErrorBody::new(err.code(), &err.public_message())
+    }
+}
+
+/// the id of the invocation handling a request: the Lambda request id when
+/// available, else the `x-request-id` header.
+pub fn request_id(event: &Request) -> Option<String> {
+    if let Some(context) = event.extensions().get::<Context>() {
+        return Some(context.request_id.clone());
+    }
+    event
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+/// serializes an error body, the single way error responses are written.
+pub fn error_body_response(status_code: StatusCode, body: &ErrorBody) -> Response<String> {
+    response(status_code, serde_json::to_string(body).unwrap())
+}
+
+/// maps an error to its status and body, tagged with the id of the request.
+pub fn error_response(event: &Request, err: &AppError) -> Response<String> {
+    error_body_response(
+        err.status_code(),
+        &ErrorBody::from(err).with_request_id(request_id(event)),
+    )
+}
+
 pub fn internal_server_error(err: AppError) -> Response<String> {
-    let status_code = StatusCode::INTERNAL_SERVER_ERROR;
-    let status = status_code.as_u16();
-    let body = json!({
-        "status": status,
-        "error": err,
-    }).to_string();
-    Response::builder()
-        .status(status_code)
-        .header("Content-Type", "application/json")
-        .body(body)
-        .unwrap()
+    error_body_response(StatusCode::INTERNAL_SERVER_ERROR, &ErrorBody::from(&err))
+}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::Body;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn error_response_uses_the_error_body() {
+        let event = http::Request::builder()
+            .header("x-request-id", "req-42")
+            .body(Body::Empty)
+            .unwrap();
+        let res = error_response(&event, &AppError::Validation("bad input".to_owned()));
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(res.body()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "code": "validation_error",
+                "message": "bad input",
+                "requestId": "req-42",
+            })
+        );
+    }
+
+    #[test]
+    fn error_body_hides_backend_failures() {
+        let err = AppError::backend("ResourceNotFoundException: table sessions-prod");
+        let body = ErrorBody::from(&err);
+
+        assert_eq!(body.code, "backend_error");
+        assert_eq!(body.message, "internal error");
+    }
+
+    #[test]
+    fn error_body_lists_field_errors() {
+        let body = ErrorBody::new("validation_error", "invalid query")
+            .with_field_error("limit", "must be a number")
+            .with_details(json!({ "max": 100 }));
+
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            json!({
+                "code": "validation_error",
+                "message": "invalid query",
+                "details": { "max": 100 },
+                "fieldErrors": [{ "field": "limit", "message": "must be a number" }],
+            })
+        );
+    }
 }